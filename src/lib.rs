@@ -0,0 +1,3 @@
+pub mod parser;
+pub mod request;
+pub mod traits;
@@ -1,17 +1,110 @@
-mod parser;
-mod request;
-mod traits;
-
-use crate::request::client::Client;
-use crate::traits::from_bencode::CreateFromBencode;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use ttorrent::request::client::{Client, log_recheck};
+use ttorrent::request::config::ClientConfig;
+use ttorrent::request::ip_filter::IpFilter;
+use ttorrent::request::mse::EncryptionMode;
+use ttorrent::request::proxy::ProxyConfig;
+use ttorrent::request::storage::{Preallocation, StorageBackend};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Encryption {
+    PreferPlaintext,
+    PreferEncrypted,
+    RequireEncrypted,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Storage {
+    File,
+    Mmap,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Allocation {
+    Sparse,
+    Full,
+    None,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hash the data already on disk, keep the valid pieces as resume data and exit
+    Recheck,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long)]
     file: String,
+    /// Number of block requests kept on the wire for every peer, adaptive if not set
+    #[arg(long)]
+    queue_depth: Option<usize>,
+    /// Seconds before a block that didn't arrive is requested again
+    #[arg(long, default_value_t = 15)]
+    block_timeout: u64,
+    /// Keep seeding after the download until this share ratio is reached
+    #[arg(long)]
+    seed_ratio: Option<f64>,
+    /// Keep seeding after the download for this many minutes
+    #[arg(long)]
+    seed_minutes: Option<u64>,
+    /// Port where other peers can connect to us
+    #[arg(short, long, default_value_t = 6881)]
+    port: u16,
+    /// Maximum number of peer connections, incoming and outgoing
+    #[arg(long, default_value_t = 50)]
+    max_connections: usize,
+    /// Peers unchoked for their rate, one more is unchoked optimistically
+    #[arg(long, default_value_t = 4)]
+    upload_slots: usize,
+    /// Seconds without any message before a peer is disconnected
+    #[arg(long, default_value_t = 120)]
+    idle_timeout: u64,
+    /// Seconds without a block from a peer we want pieces from before it is replaced
+    #[arg(long, default_value_t = 180)]
+    snub_timeout: u64,
+    /// Seconds without a block from a peer we want pieces from before we stop unchoking it
+    #[arg(long, default_value_t = 60)]
+    anti_snub_interval: u64,
+    /// Upload limit in KiB/s, unlimited if not set
+    #[arg(long)]
+    upload_limit: Option<u64>,
+    /// Download limit in KiB/s, unlimited if not set
+    #[arg(long)]
+    download_limit: Option<u64>,
+    /// Protocol encryption (MSE/PE) of the peer connections
+    #[arg(long, value_enum, default_value_t = Encryption::PreferPlaintext)]
+    encryption: Encryption,
+    /// Proxy for trackers and peers, socks5://[user:password@]host:port or http://...
+    #[arg(long)]
+    proxy: Option<String>,
+    /// Connect to peers over uTP when they answer it, and accept uTP connections
+    #[arg(long)]
+    utp: bool,
+    /// Blocklist in eMule ipfilter.dat or PeerGuardian P2P format
+    #[arg(long)]
+    ip_filter: Option<String>,
+    /// MiB of downloaded pieces kept in memory while they are written to disk
+    #[arg(long, default_value_t = 32)]
+    write_cache: usize,
+    /// Directory where the downloaded file and its resume data are kept
+    #[arg(long)]
+    download_dir: Option<String>,
+    /// How the downloaded file is read and written, mmap suits local SSDs
+    #[arg(long, value_enum, default_value_t = Storage::File)]
+    storage: Storage,
+    /// How disk space is reserved for the download, mmap always reserves it all
+    #[arg(long, value_enum, default_value_t = Allocation::Sparse)]
+    preallocation: Allocation,
+    /// Download the pieces in order, so the start of the file can be used before the end arrives
+    #[arg(long)]
+    sequential: bool,
 }
 
 #[tokio::main]
@@ -19,7 +112,54 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Args::parse();
     let bencode_byte = fs::read(&args.file)?;
-    let one_client = Client::new(&bencode_byte);
-    one_client.download_torrent().await?;
+    let config = ClientConfig {
+        request_queue_depth: args.queue_depth,
+        block_timeout: Duration::from_secs(args.block_timeout),
+        seed_ratio: args.seed_ratio,
+        seed_time: args.seed_minutes.map(|m| Duration::from_secs(m * 60)),
+        listen_port: Some(args.port),
+        max_connections: args.max_connections,
+        upload_slots: args.upload_slots,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        snub_timeout: Duration::from_secs(args.snub_timeout),
+        anti_snub_interval: Duration::from_secs(args.anti_snub_interval),
+        upload_limit: args.upload_limit.map(|kib| kib * 1024),
+        download_limit: args.download_limit.map(|kib| kib * 1024),
+        encryption: match args.encryption {
+            Encryption::PreferPlaintext => EncryptionMode::PreferPlaintext,
+            Encryption::PreferEncrypted => EncryptionMode::PreferEncrypted,
+            Encryption::RequireEncrypted => EncryptionMode::RequireEncrypted,
+        },
+        proxy: args
+            .proxy
+            .as_deref()
+            .map(ProxyConfig::from_url)
+            .transpose()?,
+        utp: args.utp,
+        ip_filter: match &args.ip_filter {
+            Some(path) => Arc::new(IpFilter::load(path)?),
+            None => Arc::default(),
+        },
+        write_cache_size: args.write_cache * 1024 * 1024,
+        download_dir: args.download_dir.unwrap_or_default().into(),
+        storage: match args.storage {
+            Storage::File => StorageBackend::File,
+            Storage::Mmap => StorageBackend::Mmap,
+        },
+        preallocation: match args.preallocation {
+            Allocation::Sparse => Preallocation::Sparse,
+            Allocation::Full => Preallocation::Full,
+            Allocation::None => Preallocation::None,
+        },
+        sequential: args.sequential,
+        ..ClientConfig::default()
+    };
+    let one_client = Client::new(&bencode_byte, config);
+    match args.command {
+        Some(Command::Recheck) => {
+            one_client.recheck(log_recheck).await?;
+        }
+        None => one_client.download_torrent().await?,
+    }
     Ok(())
 }
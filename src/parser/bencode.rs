@@ -5,8 +5,6 @@ const START_INTEGER: u8 = b'i';
 const START_LIST: u8 = b'l';
 const START_DICTIONARY: u8 = b'd';
 const END_INTEGER_LIST_DICTIONARY: u8 = b'e';
-const INTEGER_MINUS_SIGN: u8 = b'-';
-const ZERO: u8 = b'0';
 const END_SIZE_OF_STRING: u8 = b':';
 
 const START_STRING: u8 = b'1';
@@ -81,7 +79,7 @@ impl BencodeValue {
 }
 
 fn parse_string(input_slice: &[u8]) -> (BencodeValue, usize) {
-    if input_slice.len() < 1 {
+    if input_slice.is_empty() {
         return (BencodeValue::Error("Stringa troppo corta".to_string()), 0);
     }
     let pos_end_string = input_slice
@@ -101,7 +99,7 @@ fn parse_string(input_slice: &[u8]) -> (BencodeValue, usize) {
 }
 
 fn parse_integer(input_slice: &[u8]) -> (BencodeValue, usize) {
-    if input_slice.len() < 1 {
+    if input_slice.is_empty() {
         return (BencodeValue::Error("Integer troppo corto".to_string()), 0);
     }
     let end_of_integer = input_slice
@@ -137,7 +135,7 @@ fn parse_dictionary(input_slice: &[u8]) -> (BencodeValue, usize) {
 fn parse_list(input_slice: &[u8]) -> (BencodeValue, usize) {
     let mut pos = 1;
     let mut values = Vec::<BencodeValue>::new();
-    if input_slice.len() < 1 {
+    if input_slice.is_empty() {
         return (BencodeValue::Error("Error in parsing".to_string()), 0);
     }
     while pos < input_slice.len() {
@@ -154,10 +152,10 @@ fn parse_list(input_slice: &[u8]) -> (BencodeValue, usize) {
 
 pub fn parse_bencode(input_slice: &[u8]) -> (BencodeValue, usize) {
     match input_slice[0] {
-        START_INTEGER => parse_integer(&input_slice),
-        x if x >= START_STRING && x <= END_STRING => parse_string(&input_slice),
-        START_LIST => parse_list(&input_slice),
-        START_DICTIONARY => parse_dictionary(&input_slice),
+        START_INTEGER => parse_integer(input_slice),
+        x if (START_STRING..=END_STRING).contains(&x) => parse_string(input_slice),
+        START_LIST => parse_list(input_slice),
+        START_DICTIONARY => parse_dictionary(input_slice),
         _ => (BencodeValue::Error("Error in parsing".to_string()), 0),
     }
 }
@@ -1,4 +1,3 @@
-use crate::traits::from_bencode::CreateFromBencode;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
@@ -6,6 +5,15 @@ use std::net::SocketAddr;
 pub struct Peer {
     pub ip: String,
     pub port: usize,
+    #[serde(rename = "peer id", default, with = "serde_bytes")]
+    pub peer_id: Option<Vec<u8>>,
+}
+
+//a peer from the tracker, the peer id is only known when the response is not compact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackerPeer {
+    pub address: SocketAddr,
+    pub peer_id: Option<[u8; 20]>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,25 +25,30 @@ impl AnnounceResponse {
     pub fn get_peers_number(&self) -> usize {
         self.peers.len()
     }
-    fn parse_ip(p: &Peer) -> Option<SocketAddr> {
+    fn parse_ip(p: &Peer) -> Option<TrackerPeer> {
         let full_address = format!("{}:{}", p.ip, p.port);
-        match full_address.parse::<SocketAddr>() {
-            Err(_e) => None,
-            Ok(ip) => Some(ip),
-        }
+        let address = full_address.parse::<SocketAddr>().ok()?;
+        Some(TrackerPeer {
+            address,
+            peer_id: p.peer_id.as_deref().and_then(|id| id.try_into().ok()),
+        })
+    }
+    pub fn get_peers(&self) -> Vec<TrackerPeer> {
+        self.peers.iter().filter_map(Self::parse_ip).collect()
     }
-    pub fn get_peers(&self) -> Vec<SocketAddr> {
-        self.peers
-            .iter()
-            .map(|p| {
-                let full_address = format!("{}:{}", p.ip, p.port);
-                match full_address.parse::<SocketAddr>() {
-                    Err(_e) => None,
-                    Ok(ip) => Some(ip),
-                }
-            })
-            .filter(|p| p.is_some())
-            .flatten()
-            .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_id_is_read_when_present() {
+        let body = b"d8:intervali1800e5:peersld2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eed2:ip9:127.0.0.24:porti6882eeee";
+        let response: AnnounceResponse = serde_bencode::from_bytes(body).unwrap();
+        let peers = response.get_peers();
+        assert_eq!(peers[0].peer_id, Some([b'a'; 20]));
+        assert_eq!(peers[1].peer_id, None);
+        assert_eq!(peers[1].address, "127.0.0.2:6882".parse().unwrap());
     }
 }
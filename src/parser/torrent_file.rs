@@ -1,6 +1,7 @@
 use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::ops::Range;
 use url::Url;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,6 +27,34 @@ impl TorrentInfo {
         }
         divided
     }
+
+    pub fn total_length(&self) -> usize {
+        self.length
+    }
+
+    pub fn number_of_pieces(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    //byte range of every file in the torrent data, in order; single file torrents have one
+    pub fn file_ranges(&self) -> Vec<Range<usize>> {
+        std::iter::once(0..self.length).collect()
+    }
+
+    //the pieces holding some of the bytes of file, the first and last may be shared with others
+    pub fn file_pieces(&self, file: usize) -> Option<Range<usize>> {
+        let bytes = self.file_ranges().get(file)?.clone();
+        if bytes.is_empty() {
+            return Some(0..0);
+        }
+        Some(bytes.start / self.piece_length..bytes.end.div_ceil(self.piece_length))
+    }
+
+    //every piece has the same size except the last one, that keeps only what is left of the file
+    pub fn piece_size(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(start))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -38,24 +67,29 @@ pub struct TorrentFile {
 
 impl TorrentFile {
     //fixme refactor duplicate code
-    pub fn build_tracker_url(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    pub fn build_tracker_url(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut all_tracker_urls: Vec<String> = vec![];
         let info_hash_encoded =
             percent_encode(&self.compute_info_hash(), NON_ALPHANUMERIC).to_string();
+        let peer_id_encoded = percent_encode(peer_id, NON_ALPHANUMERIC).to_string();
 
-        if self.announce.is_some() {
+        if let Some(announce) = &self.announce {
             let query = format!(
-                "info_hash={}&peer_id={}",
-                info_hash_encoded, "01234567890123456789"
+                "info_hash={}&peer_id={}&port={}",
+                info_hash_encoded, peer_id_encoded, port
             );
-            let mut url = Url::parse(self.announce.as_ref().unwrap())?;
+            let mut url = Url::parse(announce)?;
             url.set_query(Some(&query));
             all_tracker_urls.push(url.to_string());
-        } else if self.announce_list.is_some() {
-            for i in self.announce_list.as_ref().unwrap().iter().flatten() {
+        } else if let Some(announce_list) = &self.announce_list {
+            for i in announce_list.iter().flatten() {
                 let query = format!(
-                    "info_hash={}&peer_id={}",
-                    info_hash_encoded, "01234567890123456789"
+                    "info_hash={}&peer_id={}&port={}",
+                    info_hash_encoded, peer_id_encoded, port
                 );
                 let mut url = Url::parse(i)?;
                 url.set_query(Some(&query));
@@ -0,0 +1,115 @@
+//pieces owned by us or by a peer, bit 7 of the first byte is piece 0 like on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    number_of_pieces: usize,
+}
+
+impl Bitfield {
+    pub fn new(number_of_pieces: usize) -> Self {
+        Self {
+            bytes: vec![0; number_of_pieces.div_ceil(8)],
+            number_of_pieces,
+        }
+    }
+
+    //a peer that sent Have All
+    pub fn full(number_of_pieces: usize) -> Self {
+        Self::from_bytes(&vec![0xFF; number_of_pieces.div_ceil(8)], number_of_pieces)
+    }
+
+    //spare bits after the last piece are ignored, a short bitfield is padded with zeros
+    pub fn from_bytes(bytes: &[u8], number_of_pieces: usize) -> Self {
+        let mut bitfield = Self::new(number_of_pieces);
+        let len = bitfield.bytes.len().min(bytes.len());
+        bitfield.bytes[..len].copy_from_slice(&bytes[..len]);
+        if !number_of_pieces.is_multiple_of(8)
+            && let Some(last) = bitfield.bytes.last_mut()
+        {
+            *last &= 0xFF << (8 - number_of_pieces % 8);
+        }
+        bitfield
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.number_of_pieces
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.number_of_pieces == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.number_of_pieces && self.bytes[index / 8] & (1 << (7 - index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.number_of_pieces {
+            self.bytes[index / 8] |= 1 << (7 - index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.number_of_pieces {
+            self.bytes[index / 8] &= !(1 << (7 - index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.number_of_pieces
+    }
+
+    //true if other has at least one piece that we are missing
+    pub fn is_interested_in(&self, other: &Bitfield) -> bool {
+        self.bytes
+            .iter()
+            .zip(other.bytes.iter())
+            .any(|(mine, theirs)| theirs & !mine != 0)
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.number_of_pieces).filter(|index| self.has(*index))
+    }
+
+    //pieces set in self and not in other
+    pub fn difference(&self, other: &Bitfield) -> Vec<usize> {
+        (0..self.number_of_pieces)
+            .filter(|i| self.has(*i) && !other.has(*i))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitfield_ignores_spare_bits() {
+        let bitfield = Bitfield::from_bytes(&[0b1000_0001, 0b1111_1111], 10);
+        assert!(bitfield.has(0));
+        assert!(bitfield.has(7));
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.count(), 4);
+    }
+
+    #[test]
+    fn bitfield_difference_and_interest() {
+        let mut ours = Bitfield::new(12);
+        let mut theirs = Bitfield::new(12);
+        ours.set(1);
+        theirs.set(1);
+        assert!(!ours.is_interested_in(&theirs));
+        theirs.set(11);
+        assert!(ours.is_interested_in(&theirs));
+        assert_eq!(theirs.difference(&ours), vec![11]);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::request::request_queue::BLOCK_LENGTH;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+//bad copies remembered for one piece, the senders of later ones are only avoided
+const MAX_COPIES_PER_PIECE: usize = 8;
+//pieces with bad copies remembered at once, past it a bad copy is only avoided, never blamed
+const MAX_BAD_PIECES: usize = 256;
+
+//a piece as it came from the peers, before the hash check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadedPiece {
+    pub index: usize,
+    pub data: Vec<u8>,
+    //who sent each block, in block order, None for blocks read back from disk
+    pub contributors: Vec<Option<IpAddr>>,
+}
+
+impl DownloadedPiece {
+    fn block(&self, block: usize) -> &[u8] {
+        let begin = (block * BLOCK_LENGTH as usize).min(self.data.len());
+        let end = (begin + BLOCK_LENGTH as usize).min(self.data.len());
+        &self.data[begin..end]
+    }
+}
+
+//a bad copy without its data, the hash of every block is enough to compare
+struct BadCopy {
+    blocks: Vec<(Option<IpAddr>, [u8; 20])>,
+}
+
+//Works out who sent corrupt data. A piece that fails the hash check is remembered until a good
+//copy arrives, then the blocks that differ from it point to the peers that sent them.
+pub struct BlameTracker {
+    max_strikes: u32,
+    bad_copies: HashMap<usize, Vec<BadCopy>>,
+    strikes: HashMap<IpAddr, u32>,
+}
+
+impl BlameTracker {
+    pub fn new(max_strikes: u32) -> Self {
+        Self {
+            max_strikes,
+            bad_copies: HashMap::new(),
+            strikes: HashMap::new(),
+        }
+    }
+
+    //returns every peer that contributed to a bad copy of the piece, it should come from others
+    pub fn on_hash_failed(&mut self, piece: DownloadedPiece) -> HashSet<IpAddr> {
+        let mut suspects: HashSet<IpAddr> = piece.contributors.iter().flatten().copied().collect();
+        if self.bad_copies.len() >= MAX_BAD_PIECES && !self.bad_copies.contains_key(&piece.index) {
+            return suspects;
+        }
+        let copies = self.bad_copies.entry(piece.index).or_default();
+        suspects.extend(
+            copies
+                .iter()
+                .flat_map(|copy| copy.blocks.iter().filter_map(|(ip, _)| *ip)),
+        );
+        if copies.len() < MAX_COPIES_PER_PIECE {
+            let blocks = piece
+                .contributors
+                .iter()
+                .enumerate()
+                .map(|(block, ip)| (*ip, Sha1::digest(piece.block(block)).into()))
+                .collect();
+            copies.push(BadCopy { blocks });
+        }
+        suspects
+    }
+
+    //returns the peers to ban, the ones that sent corrupt blocks once too often
+    pub fn on_hash_passed(&mut self, piece: &DownloadedPiece) -> Vec<IpAddr> {
+        let Some(copies) = self.bad_copies.remove(&piece.index) else {
+            return Vec::new();
+        };
+        let mut guilty = HashSet::new();
+        for copy in &copies {
+            for (block, (ip, hash)) in copy.blocks.iter().enumerate() {
+                //nobody is to blame for a block read back from disk
+                if let Some(ip) = ip
+                    && Sha1::digest(piece.block(block))[..] != hash[..]
+                {
+                    guilty.insert(*ip);
+                }
+            }
+        }
+        let mut banned = Vec::new();
+        for ip in guilty {
+            let strikes = self.strikes.entry(ip).or_default();
+            *strikes += 1;
+            if *strikes >= self.max_strikes {
+                banned.push(ip);
+            }
+        }
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_peers_with_different_blocks_are_blamed() {
+        let honest: IpAddr = [10, 0, 0, 1].into();
+        let liar: IpAddr = [10, 0, 0, 2].into();
+        let good = vec![1u8; 2 * BLOCK_LENGTH as usize];
+        let mut bad = good.clone();
+        bad[BLOCK_LENGTH as usize] = 0;
+        let mut tracker = BlameTracker::new(2);
+
+        for round in 0..2 {
+            let suspects = tracker.on_hash_failed(DownloadedPiece {
+                index: round,
+                data: bad.clone(),
+                contributors: vec![Some(honest), Some(liar)],
+            });
+            assert_eq!(suspects, HashSet::from([honest, liar]));
+            let banned = tracker.on_hash_passed(&DownloadedPiece {
+                index: round,
+                data: good.clone(),
+                contributors: vec![Some(honest), None],
+            });
+            if round == 0 {
+                assert!(banned.is_empty());
+            } else {
+                assert_eq!(banned, vec![liar]);
+            }
+        }
+        assert_eq!(tracker.strikes.get(&honest), None);
+
+        //past the limit the piece is still avoided, but nobody is remembered for it
+        for index in 0..MAX_BAD_PIECES + 1 {
+            let suspects = tracker.on_hash_failed(DownloadedPiece {
+                index,
+                data: bad.clone(),
+                contributors: vec![Some(honest), None],
+            });
+            assert_eq!(suspects, HashSet::from([honest]));
+        }
+        assert_eq!(tracker.bad_copies.len(), MAX_BAD_PIECES);
+    }
+}
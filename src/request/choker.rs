@@ -0,0 +1,208 @@
+use crate::request::stats::PeerStats;
+use crate::request::torrent_state::TorrentState;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+//the optimistic unchoke moves to another peer every 3 rounds, 30 seconds
+const OPTIMISTIC_ROUNDS: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChokerDecision {
+    pub round: u64,
+    pub seeding: bool,
+    //peer ids unchoked for their rate, the optimistic one is not included
+    pub unchoked: Vec<usize>,
+    pub optimistic: Option<usize>,
+    pub snubbed: Vec<usize>,
+}
+
+//BEP 3 tit-for-tat: every round the peers that give us the best rate are unchoked, while
+//seeding the ones we upload to fastest. One more peer is unchoked optimistically so new peers
+//get a chance to show their rate.
+pub struct Choker {
+    upload_slots: usize,
+    //a peer we are interested in that sends no block for this long is snubbing us
+    anti_snub_interval: Duration,
+    round: u64,
+    optimistic: Option<usize>,
+    //when a peer was last picked as optimistic unchoke, the one waiting longest goes next
+    last_optimistic: HashMap<usize, Instant>,
+    //downloaded and uploaded bytes at the previous round
+    previous: HashMap<usize, (u64, u64)>,
+    last_round: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize, anti_snub_interval: Duration) -> Self {
+        Self {
+            upload_slots,
+            anti_snub_interval,
+            round: 0,
+            optimistic: None,
+            last_optimistic: HashMap::new(),
+            previous: HashMap::new(),
+            last_round: None,
+        }
+    }
+
+    pub async fn run(mut self, state: Arc<TorrentState>) {
+        let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let seeding = state.bitfield().is_complete();
+            let decision = self.run_round(&state.peers(), seeding, Instant::now());
+            debug!("Choker round {:?}", decision);
+            state.set_choker_decision(decision);
+        }
+    }
+
+    pub fn run_round(
+        &mut self,
+        peers: &[Arc<PeerStats>],
+        seeding: bool,
+        now: Instant,
+    ) -> ChokerDecision {
+        self.round += 1;
+        let elapsed = self
+            .last_round
+            .map_or(CHOKE_INTERVAL, |last| now.duration_since(last))
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        self.last_round = Some(now);
+
+        let mut previous = HashMap::with_capacity(peers.len());
+        let mut snapshots = Vec::with_capacity(peers.len());
+        for peer in peers {
+            let mut snapshot = peer.snapshot();
+            let (downloaded, uploaded) = self.previous.get(&snapshot.id).copied().unwrap_or((0, 0));
+            snapshot.download_rate = (snapshot.downloaded - downloaded) as f64 / elapsed;
+            snapshot.upload_rate = (snapshot.uploaded - uploaded) as f64 / elapsed;
+            let last_block = snapshot.last_block.unwrap_or(snapshot.connected_at);
+            snapshot.snubbed =
+                snapshot.am_interested && now.duration_since(last_block) >= self.anti_snub_interval;
+            previous.insert(snapshot.id, (snapshot.downloaded, snapshot.uploaded));
+            snapshots.push(snapshot);
+        }
+        self.previous = previous;
+        self.last_optimistic
+            .retain(|id, _| snapshots.iter().any(|s| s.id == *id));
+
+        //anti-snubbing: a peer that doesn't upload to us only gets optimistic unchokes
+        let mut candidates: Vec<_> = snapshots
+            .iter()
+            .filter(|s| s.peer_interested && (seeding || !s.snubbed))
+            .collect();
+        if seeding {
+            candidates.sort_by(|a, b| b.upload_rate.total_cmp(&a.upload_rate));
+        } else {
+            candidates.sort_by(|a, b| b.download_rate.total_cmp(&a.download_rate));
+        }
+        let unchoked: Vec<usize> = candidates
+            .iter()
+            .take(self.upload_slots)
+            .map(|s| s.id)
+            .collect();
+
+        let current_is_valid = self.optimistic.is_some_and(|id| {
+            !unchoked.contains(&id) && snapshots.iter().any(|s| s.id == id && s.peer_interested)
+        });
+        if !current_is_valid || (self.round - 1).is_multiple_of(OPTIMISTIC_ROUNDS) {
+            self.optimistic = snapshots
+                .iter()
+                .filter(|s| s.peer_interested && !unchoked.contains(&s.id))
+                .min_by_key(|s| (self.last_optimistic.get(&s.id).copied(), s.id))
+                .map(|s| s.id);
+            if let Some(id) = self.optimistic {
+                self.last_optimistic.insert(id, now);
+            }
+        }
+
+        for (peer, snapshot) in peers.iter().zip(&snapshots) {
+            let optimistic = self.optimistic == Some(snapshot.id);
+            peer.update(|s| {
+                s.download_rate = snapshot.download_rate;
+                s.upload_rate = snapshot.upload_rate;
+                s.snubbed = snapshot.snubbed;
+                s.optimistic = optimistic;
+            });
+            peer.set_choke(!optimistic && !unchoked.contains(&snapshot.id));
+        }
+
+        ChokerDecision {
+            round: self.round,
+            seeding,
+            unchoked,
+            optimistic: self.optimistic,
+            snubbed: snapshots
+                .iter()
+                .filter(|s| s.snubbed)
+                .map(|s| s.id)
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::config::ClientConfig;
+
+    fn interested_peer(id: usize, downloaded: u64) -> Arc<PeerStats> {
+        let peer = Arc::new(PeerStats::new(
+            id,
+            ([127, 0, 0, 1], 6881).into(),
+            [id as u8; 20],
+        ));
+        peer.set_peer_interested(true);
+        peer.set_am_interested(true);
+        peer.add_downloaded(downloaded);
+        peer
+    }
+
+    #[test]
+    fn fastest_peers_are_unchoked_and_one_optimistic() {
+        let peers: Vec<_> = (0..5)
+            .map(|id| interested_peer(id, id as u64 * 1000))
+            .collect();
+        let mut choker = Choker::new(2, ClientConfig::default().anti_snub_interval);
+        let decision = choker.run_round(&peers, false, Instant::now());
+        assert_eq!(decision.unchoked, vec![4, 3]);
+        assert_eq!(decision.optimistic, Some(0));
+        assert!(!peers[0].snapshot().am_choking);
+        assert!(peers[1].snapshot().am_choking);
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_three_rounds() {
+        let peers: Vec<_> = (0..4).map(|id| interested_peer(id, 0)).collect();
+        let mut choker = Choker::new(1, ClientConfig::default().anti_snub_interval);
+        let start = Instant::now();
+        let first = choker.run_round(&peers, false, start).optimistic;
+        let second = choker
+            .run_round(&peers, false, start + CHOKE_INTERVAL)
+            .optimistic;
+        assert_eq!(first, second);
+        choker.run_round(&peers, false, start + CHOKE_INTERVAL * 2);
+        let rotated = choker
+            .run_round(&peers, false, start + CHOKE_INTERVAL * 3)
+            .optimistic;
+        assert_ne!(first, rotated);
+    }
+
+    #[test]
+    fn snubbing_peer_loses_its_slot() {
+        let peers: Vec<_> = (0..2).map(|id| interested_peer(id, 1000)).collect();
+        //snubbed well before the peer stream would drop it
+        let config = ClientConfig::default();
+        assert!(config.anti_snub_interval < config.snub_timeout);
+        let now = Instant::now() + config.anti_snub_interval;
+        peers[0].update(|s| s.last_block = Some(now));
+        let mut choker = Choker::new(1, ClientConfig::default().anti_snub_interval);
+        let decision = choker.run_round(&peers, false, now);
+        assert_eq!(decision.unchoked, vec![0]);
+        assert_eq!(decision.snubbed, vec![1]);
+    }
+}
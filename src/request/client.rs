@@ -1,18 +1,48 @@
-use crate::parser::peers::AnnounceResponse;
+use crate::parser::peers::{AnnounceResponse, TrackerPeer};
 use crate::parser::torrent_file::TorrentFile;
 use sha1::{Digest, Sha1};
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::request::bitfield::Bitfield;
+use crate::request::blame::{BlameTracker, DownloadedPiece};
+use crate::request::choker::Choker;
+use crate::request::config::ClientConfig;
+use crate::request::listener::{IncomingPeer, PeerListener};
+use crate::request::peer_pool::PeerSource;
 use crate::request::peer_stream::PeerStream;
-use crate::request::storage::TorrentPersisted;
-use async_channel::{RecvError, unbounded};
-use log::{error, info};
+use crate::request::piece_picker::Priority;
+use crate::request::proxy::ProxyKind;
+use crate::request::rate_limit::BandwidthLimits;
+use crate::request::recheck::{RecheckProgress, recheck};
+use crate::request::relocate::{MoveJournal, MoveProgress, move_file, same_filesystem};
+use crate::request::resume::{Resume, ResumeFile};
+use crate::request::stats::TorrentStats;
+use crate::request::storage::{DeferredFile, FailedStorage, Storage, disk_space, open_file};
+use crate::request::stream::TorrentReader;
+use crate::request::task::AbortOnDrop;
+use crate::request::torrent_state::TorrentState;
+use crate::request::utp::UtpSocket;
+use crate::request::write_cache::WriteCache;
+use async_channel::{RecvError, Sender, bounded};
+use log::{debug, error, info, warn};
 use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
 use tokio::time::error::Elapsed;
 
-const PAYLOAD_LENGTH: u32 = 16384;
+const SEED_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+//announced to the tracker when we are not listening
+const DEFAULT_PORT: u16 = 6881;
+//the connector looks for peers to dial at least this often
+const CONNECT_INTERVAL: Duration = Duration::from_secs(5);
+//downloaded pieces waiting for the hash check, peers wait to hand over more
+const DOWNLOADED_QUEUE: usize = 4;
+
+//Azureus style prefix, client TT version 0.1.0
+const PEER_ID_PREFIX: &[u8; 8] = b"-TT0100-";
 
 #[derive(Debug, Error)]
 pub enum ClientError {
@@ -40,6 +70,40 @@ pub enum ClientError {
     ChannelReceiverError,
     #[error("Cannot fetch peers: {0}")]
     CannotFetchPeers(String),
+    #[error("Peer sent a malformed message: {0}")]
+    MalformedMessage(String),
+    #[error("Incoming peer asked for a torrent we don't have")]
+    UnknownInfoHash,
+    #[error("Peer handshake is for another torrent than its encryption handshake")]
+    InfoHashMismatch,
+    #[error("Peer sent a message id {0} of {1} bytes, over the limit")]
+    FrameTooLarge(u8, usize),
+    #[error("Peer doesn't speak the BitTorrent protocol")]
+    UnknownProtocol,
+    #[error("Proxy failed: {0}")]
+    ProxyFailed(String),
+    #[error("Address {0} is blocked by the IP filter")]
+    Blocked(IpAddr),
+    #[error("Not enough disk space: {needed} bytes needed, {available} available")]
+    LowDiskSpace { needed: u64, available: u64 },
+    #[error("Only the storage the client opened itself can be moved, while the download runs")]
+    NotMovable,
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Already connected to this peer id")]
+    DuplicatePeer,
+    #[error("Peer id is not the one the tracker announced")]
+    PeerIdMismatch,
+    #[error("Peer sent nothing for {0:?}")]
+    PeerIdle(Duration),
+    #[error("Peer sent no block for {0:?}")]
+    PeerSnubbing(Duration),
+    #[error("Peer was banned")]
+    PeerBanned,
+    #[error("Peer doesn't support the encryption we require")]
+    EncryptionRequired,
+    #[error("Encrypted handshake failed: {0}")]
+    EncryptionFailed(&'static str),
 }
 
 impl From<Elapsed> for ClientError {
@@ -48,7 +112,7 @@ impl From<Elapsed> for ClientError {
     }
 }
 impl From<async_channel::RecvError> for ClientError {
-    fn from(value: RecvError) -> Self {
+    fn from(_: RecvError) -> Self {
         ClientError::Timeout
     }
 }
@@ -56,27 +120,138 @@ impl From<async_channel::RecvError> for ClientError {
 pub struct Client {
     torrent_file: TorrentFile,
     client_peer_id: [u8; 20],
+    config: ClientConfig,
+    listener: Option<PeerListener>,
+    global_limits: BandwidthLimits,
+    //taken by download_torrent, a file named after the torrent if not set
+    storage: StdMutex<Option<Box<dyn Storage>>>,
+    //set while download_torrent runs, it backs the stats
+    state: OnceLock<Arc<TorrentState>>,
+    //directory of the data, changed by move_storage
+    location: StdMutex<PathBuf>,
+    //set while download_torrent runs with storage we opened, move_storage saves through it
+    resume: OnceLock<ResumeFile>,
+}
+
+//logs every tenth of a recheck
+pub fn log_recheck(progress: RecheckProgress) {
+    let step = (progress.total / 10).max(1);
+    if progress.checked.is_multiple_of(step) || progress.checked == progress.total {
+        info!(
+            "Checked {}/{} pieces, {} valid",
+            progress.checked, progress.total, progress.valid
+        );
+    }
+}
+
+//Unique for every client, two instances on the same machine would otherwise take each other for
+//a connection to themselves.
+fn generate_peer_id() -> [u8; 20] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = Sha1::new();
+    hasher.update(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_be_bytes(),
+    );
+    hasher.update(std::process::id().to_be_bytes());
+    hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    let hash = hasher.finalize();
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for (byte, random) in peer_id[8..].iter_mut().zip(hash) {
+        *byte = b"0123456789abcdefghijklmnopqrstuvwxyz"[random as usize % 36];
+    }
+    peer_id
 }
 
 impl Client {
-    pub fn new(bencode_byte: &[u8]) -> Client {
-        let client_per_id = *b"01234567890123456789";
-        let torrent_file: TorrentFile = serde_bencode::from_bytes(&bencode_byte).unwrap();
+    pub fn new(bencode_byte: &[u8], config: ClientConfig) -> Client {
+        let torrent_file: TorrentFile = serde_bencode::from_bytes(bencode_byte).unwrap();
         Self {
+            location: StdMutex::new(config.download_dir.clone()),
             torrent_file,
-            client_peer_id: client_per_id,
+            client_peer_id: generate_peer_id(),
+            config,
+            listener: None,
+            global_limits: BandwidthLimits::unlimited(),
+            storage: StdMutex::new(None),
+            state: OnceLock::new(),
+            resume: OnceLock::new(),
         }
     }
 
-    async fn find_peer(&self) -> Result<Vec<SocketAddr>, ClientError> {
+    //None until download_torrent has started
+    pub fn stats(&self) -> Option<TorrentStats> {
+        self.state.get().map(|state| state.stats())
+    }
+
+    //limits shared by every client given the same value
+    pub fn with_global_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.global_limits = limits;
+        self
+    }
+
+    //bytes per second, None is unlimited; ignored until download_torrent starts
+    pub fn set_torrent_limits(&self, upload: Option<u64>, download: Option<u64>) {
+        if let Some(state) = self.state.get() {
+            state.limits().set(upload, download);
+        }
+    }
+
+    //returns false if there is no connection with that id
+    pub fn set_peer_limits(&self, id: usize, upload: Option<u64>, download: Option<u64>) -> bool {
+        let Some(peer) = self.state.get().and_then(|state| state.peer(id)) else {
+            return false;
+        };
+        peer.limits().set(upload, download);
+        true
+    }
+
+    //false if the download hasn't started or the torrent has no such file
+    pub fn set_file_priority(&self, file: usize, priority: Priority) -> bool {
+        self.state
+            .get()
+            .is_some_and(|state| state.set_file_priority(file, priority))
+    }
+
+    //the file read while it downloads, None if the download hasn't started or there is no such file
+    pub fn reader(&self, file: usize) -> Option<TorrentReader> {
+        TorrentReader::new(Arc::clone(self.state.get()?), file)
+    }
+
+    //keep the torrent content in storage instead of a file named after the torrent
+    pub fn with_storage(self, storage: impl Storage + 'static) -> Self {
+        *self.storage.lock().unwrap() = Some(Box::new(storage));
+        self
+    }
+
+    //share one listening port between several clients instead of binding config.listen_port
+    pub fn with_listener(mut self, listener: PeerListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    async fn find_peer(&self, port: u16) -> Result<Vec<TrackerPeer>, ClientError> {
         let all_tracker = self
             .torrent_file
-            .build_tracker_url()
+            .build_tracker_url(&self.client_peer_id, port)
+            .map_err(|e| ClientError::CannotFetchPeers(e.to_string()))?;
+        let mut all_peer: Vec<TrackerPeer> = vec![];
+        let mut http = reqwest::Client::builder();
+        if let Some(proxy) = &self.config.proxy {
+            http = http.proxy(proxy.to_reqwest()?);
+        }
+        let http = http
+            .build()
             .map_err(|e| ClientError::CannotFetchPeers(e.to_string()))?;
-        let mut all_peer: Vec<SocketAddr> = vec![];
 
         for tracker in all_tracker {
-            let response = reqwest::get(tracker)
+            let response = http
+                .get(tracker)
+                .send()
                 .await
                 .map_err(|e| ClientError::CannotFetchPeers(e.to_string()))?;
             let body_bytes = response
@@ -90,128 +265,629 @@ impl Client {
         Ok(all_peer)
     }
 
-    fn piece_hash_is_correct(piece: &Vec<u8>, checksum: [u8; 20]) -> bool {
+    fn piece_hash_is_correct(piece: &[u8], checksum: [u8; 20]) -> bool {
         let mut hasher = Sha1::new();
-        hasher.update(&piece);
+        hasher.update(piece);
         let hash = hasher.finalize();
-        let hash_value: [u8; 20] = hash.try_into().unwrap();
+        let hash_value: [u8; 20] = hash.into();
         hash_value == checksum
     }
 
-    pub async fn download_torrent(&self) -> Result<(), ClientError> {
-        let peer = self.find_peer().await?;
-        let number_of_peers_downloader = peer.len();
-        let (transmitter_work, receiver_work) = unbounded::<usize>();
-        let (transmitter_piece, receiver_piece) = unbounded::<(usize, Vec<u8>)>();
-        //fixme investigate arc
-        let torrent_file = Arc::new(self.torrent_file.clone());
-        let client_id = Arc::new(self.client_peer_id.clone());
-        let peer_info = Arc::new(peer.clone());
-
-        //create peer to download
-        for slave_id in 1..=number_of_peers_downloader {
-            let r_work = receiver_work.clone();
-            let t_piece = transmitter_piece.clone();
-            let t_work = transmitter_work.clone();
-            let t_file = Arc::clone(&torrent_file);
-            let c_id = Arc::clone(&client_id);
-            let p_info = Arc::clone(&peer_info);
+    async fn listener(&self) -> Result<Option<PeerListener>, ClientError> {
+        if let Some(listener) = &self.listener {
+            return Ok(Some(listener.clone()));
+        }
+        match self.config.listen_port {
+            Some(port) => {
+                let connections = Arc::new(Semaphore::new(self.config.max_connections));
+                let address = SocketAddr::from(([0, 0, 0, 0], port));
+                Ok(Some(
+                    PeerListener::bind(
+                        address,
+                        connections,
+                        self.config.encryption,
+                        Arc::clone(&self.config.ip_filter),
+                        //a uTP socket of our own would give our address away, see utp_socket
+                        self.config.utp && self.config.proxy.is_none(),
+                    )
+                    .await?,
+                ))
+            }
+            None => Ok(None),
+        }
+    }
 
-            tokio::spawn(async move {
-                println!("Creating slave downloader {} ", slave_id);
-                let peer_stream =
-                    PeerStream::new(slave_id, &p_info[slave_id], &t_file, &c_id).await;
-                match peer_stream {
-                    Ok(mut stream) => {
-                        //keep reading if there is work to do
-                        while let Ok(piece_id) = r_work.recv().await {
-                            let downloaded_piece = stream.download_piece(piece_id).await;
-                            match downloaded_piece {
-                                Ok(piece) => {
-                                    let _ = t_piece.send(piece).await;
-                                }
-                                _ => {
-                                    //if it was unable to download the piece, put back the work in the queue
-                                    t_work.send(piece_id).await;
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        //fixme try recreate the stream until there is no more peer or we have exactly number of peers thread
-                        println!("Error creating stream");
-                    }
+    //outgoing uTP leaves from the listen port when there is one, peers can dial it back. With
+    //a proxy it goes through a SOCKS5 UDP association, HTTP proxies can't carry it.
+    async fn utp_socket(
+        &self,
+        listener: Option<&PeerListener>,
+    ) -> Result<Option<Arc<UtpSocket>>, ClientError> {
+        match &self.config.proxy {
+            None => match listener.and_then(PeerListener::utp) {
+                Some(socket) => Ok(Some(socket)),
+                None => Ok(Some(Arc::new(
+                    UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?,
+                ))),
+            },
+            Some(proxy) if proxy.kind == ProxyKind::Socks5 => match proxy.udp_associate().await {
+                Ok(datagram) => Ok(Some(Arc::new(UtpSocket::through_proxy(datagram)))),
+                Err(e) => {
+                    warn!("Proxy doesn't relay UDP, uTP is off: {}", e);
+                    Ok(None)
                 }
-            });
+            },
+            Some(_) => {
+                info!("uTP is off, HTTP proxies only carry TCP");
+                Ok(None)
+            }
         }
+    }
+
+    pub async fn download_torrent(&self) -> Result<(), ClientError> {
+        let listener = self.listener().await?;
+        //the limit is shared with the listener so incoming and outgoing connections add up
+        let connections = match &listener {
+            Some(listener) => listener.connections(),
+            None => Arc::new(Semaphore::new(self.config.max_connections)),
+        };
+        let port = listener
+            .as_ref()
+            .map_or(DEFAULT_PORT, |listener| listener.local_addr().port());
+        let tracker_peers = self.find_peer(port).await?;
+        let (transmitter_piece, receiver_piece) = bounded::<DownloadedPiece>(DOWNLOADED_QUEUE);
 
         let pieces = self.torrent_file.info.get_divided_pieces();
         let number_of_pieces = pieces.len();
-        let mut all_pieces = HashSet::with_capacity(number_of_pieces);
-        all_pieces.extend(0..number_of_pieces);
 
-        //fixme I already know the dimension of everything here following the torrent, i JUST NEED
-        //to store the dimension of a flush, in order to save memory
-        let mut downloaded_file: HashMap<usize, Vec<u8>> = HashMap::with_capacity(number_of_pieces);
-        let file_dimension = number_of_pieces as u64 * self.torrent_file.info.piece_length as u64;
-        let mut persisted_file =
-            TorrentPersisted::new(&self.torrent_file.info.name, file_dimension).await?;
+        let supplied = self.storage.lock().unwrap().take();
+        if supplied.is_none() {
+            self.recover_move().await?;
+        }
+        let resume_file = self.resume_file(supplied.is_some());
+        if supplied.is_none() {
+            let _ = self.resume.set(resume_file.clone());
+        }
+        let resume = resume_file
+            .load(self.torrent_file.compute_info_hash(), number_of_pieces)
+            .await;
+        let (mut storage, room) = self.open_storage(supplied).await?;
+
+        //the pieces in the resume data are the bitfield we send to peers after the handshake
+        let mut resume_peers = Vec::new();
+        let mut partial = Vec::new();
+        let mut bitfield = match resume {
+            Resume::Valid(data) => {
+                resume_peers = data.peer_addresses();
+                partial = data.partial;
+                Bitfield::from_bytes(&data.pieces, number_of_pieces)
+            }
+            Resume::Missing => Bitfield::new(number_of_pieces),
+            Resume::Stale(reason) => {
+                warn!(
+                    "Cannot trust the resume data ({}), checking every piece",
+                    reason
+                );
+                recheck(storage.as_mut(), &self.torrent_file.info, log_recheck).await?
+            }
+        };
+        //blocks left on disk by the last run, the peers only ask for the rest
+        let mut unfinished = Vec::new();
+        for piece in partial {
+            if piece.index >= number_of_pieces || bitfield.has(piece.index) {
+                continue;
+            }
+            let piece_size = self.torrent_file.info.piece_size(piece.index);
+            let blocks = match piece.read(storage.as_mut(), piece_size).await {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    debug!("Cannot read the blocks of piece {}: {}", piece.index, e);
+                    continue;
+                }
+            };
+            if blocks.iter().all(Option::is_some) {
+                //written in full but never checked, the last run stopped in between
+                let data: Vec<u8> = blocks.into_iter().flatten().flat_map(|b| b.data).collect();
+                if Self::piece_hash_is_correct(&data, pieces[piece.index]) {
+                    bitfield.set(piece.index);
+                }
+            } else {
+                unfinished.push((piece.index, blocks));
+            }
+        }
+        let piece_already_downloaded = bitfield.count();
+        let state = Arc::new(TorrentState::new(
+            self.torrent_file.clone(),
+            self.client_peer_id,
+            self.config.clone(),
+            bitfield,
+            storage,
+            self.global_limits.clone(),
+        ));
+        for (index, blocks) in unfinished {
+            state.restore_blocks(index, blocks);
+        }
+        //the first writes tell whether the pieces fit after all
+        state.set_disk_full(!room);
+        let mut cache = WriteCache::new(
+            Arc::clone(&state),
+            resume_file.clone(),
+            self.config.write_cache_size,
+        );
+
+        let _ = self.state.set(Arc::clone(&state));
+        let choker = AbortOnDrop(tokio::spawn(
+            Choker::new(self.config.upload_slots, self.config.anti_snub_interval)
+                .run(Arc::clone(&state)),
+        ));
 
-        //send work to slave reading from  checkpoint
-        let mut piece_to_download: HashSet<usize> = HashSet::with_capacity(number_of_pieces);
-        piece_to_download.extend(0..number_of_pieces);
-        let piece_already_downloaded = persisted_file.read_checkpoint().await?;
+        if self.config.utp
+            && let Some(socket) = self.utp_socket(listener.as_ref()).await?
+        {
+            state.set_utp_socket(socket);
+        }
+        for peer in tracker_peers {
+            state.pool().add(peer, PeerSource::Tracker);
+        }
+        for address in resume_peers {
+            let peer = TrackerPeer {
+                address,
+                peer_id: None,
+            };
+            state.pool().add(peer, PeerSource::Tracker);
+        }
+        let connector = AbortOnDrop(tokio::spawn(Self::connect_peers(
+            Arc::clone(&state),
+            Arc::clone(&connections),
+            transmitter_piece.clone(),
+        )));
 
-        for piece in piece_to_download.difference(&piece_already_downloaded) {
-            transmitter_work.send(*piece).await.unwrap();
+        if let Some(listener) = &listener {
+            let incoming = listener.register(state.info_hash());
+            tokio::spawn(Self::accept_peers(
+                incoming,
+                Arc::clone(&state),
+                transmitter_piece.clone(),
+            ));
         }
 
+        //the picker starts with every piece missing from the resume data
         info!(
             "Total pieces: {}, Pieces still to download: {}",
             number_of_pieces,
-            piece_to_download
-                .difference(&piece_already_downloaded)
-                .count()
+            state.picker().remaining()
         );
 
-        let mut completed_pieces = piece_already_downloaded.len();
+        let mut completed_pieces = piece_already_downloaded;
 
         //keep up reading the piece that has been downloaded
         info! {"completed pieces {}", completed_pieces}
+        let mut blame = BlameTracker::new(self.config.max_corrupt_pieces);
+        let mut received = state.bitfield();
+
+        let downloading = async {
+            loop {
+                if state.wanted_pieces_completed(&received) {
+                    cache.finish().await?;
+                    break;
+                }
+
+                //a priority change may leave nothing to wait for
+                let received_piece = tokio::select! {
+                    piece = receiver_piece.recv() => piece?,
+                    _ = state.picker().wait() => continue,
+                };
+                debug!("{} of {} pieces completed", completed_pieces, pieces.len());
+
+                if Self::piece_hash_is_correct(&received_piece.data, pieces[received_piece.index]) {
+                    info!("Received piece number: {}", received_piece.index);
+                    for ip in blame.on_hash_passed(&received_piece) {
+                        warn!("Banning {} for sending corrupt data", ip);
+                        state.pool().ban(ip);
+                    }
+                    state.picker().forget_suspects(received_piece.index);
+                    //waits while the cache is full, and the peers wait on us
+                    cache
+                        .insert(received_piece.index, received_piece.data)
+                        .await?;
+                    received.set(received_piece.index);
+                    completed_pieces += 1;
+                } else {
+                    info!("Resend the piece to queue {} ", received_piece.index);
+                    let index = received_piece.index;
+                    let suspects = blame.on_hash_failed(received_piece);
+                    state.picker().put_back_avoiding(index, suspects);
+                }
+            }
+            Ok::<_, ClientError>(())
+        };
+        let downloaded = downloading.await;
+        //a reader waiting for a skipped piece gets an error instead of waiting forever
+        state.set_download_ended();
+        downloaded?;
 
+        self.seed(&state).await;
+        drop(connector);
+        drop(choker);
+        if let Some(listener) = &listener {
+            listener.unregister(&state.info_hash());
+        }
+        state.storage().lock().await.close().await?;
+        //the peers we met while seeding are worth keeping too
+        resume_file.save(&state).await?;
+        Ok(())
+    }
+
+    //Keeps target_connections peers open: dials the best candidates of the pool while there is
+    //room, then waits for a connection to end or for a backoff to expire.
+    async fn connect_peers(
+        state: Arc<TorrentState>,
+        connections: Arc<Semaphore>,
+        pieces_done: Sender<DownloadedPiece>,
+    ) {
+        let target = state.config().target_connections;
         loop {
-            if completed_pieces == pieces.len() {
-                persisted_file
-                    .write_pieces(
-                        &mut downloaded_file,
-                        self.torrent_file.info.piece_length as usize,
-                    )
-                    .await?;
-                break;
+            while state.pool().stats().connected < target {
+                //the limit is shared with incoming connections
+                let Ok(permit) = Arc::clone(&connections).try_acquire_owned() else {
+                    break;
+                };
+                let Some(peer) = state.pool().next_candidate(Instant::now()) else {
+                    break;
+                };
+                tokio::spawn(Self::connect_peer(
+                    peer,
+                    permit,
+                    Arc::clone(&state),
+                    pieces_done.clone(),
+                ));
             }
+            let retry = state
+                .pool()
+                .next_retry()
+                .map_or(CONNECT_INTERVAL, |at| {
+                    at.saturating_duration_since(Instant::now())
+                })
+                .min(CONNECT_INTERVAL);
+            tokio::select! {
+                _ = state.pool().changed() => {}
+                _ = tokio::time::sleep(retry) => {}
+            }
+        }
+    }
 
-            let received_piece = receiver_piece.recv().await?;
-            info! {"completed pieces {}", completed_pieces}
-            info! {"asd {}", pieces.len()}
+    async fn connect_peer(
+        peer: TrackerPeer,
+        _permit: OwnedSemaphorePermit,
+        state: Arc<TorrentState>,
+        pieces_done: Sender<DownloadedPiece>,
+    ) {
+        let id = state.next_connection_id();
+        debug!("{} - connecting to {}", id, peer.address);
+        let result = match PeerStream::new(id, &peer, Arc::clone(&state)).await {
+            Ok(mut stream) => {
+                state.pool().on_connected(peer.address);
+                //download and upload until the peer goes away, unfinished pieces go back in the queue
+                stream.run(&pieces_done).await.map_err(|e| (e, true))
+            }
+            Err(e) => Err((e, false)),
+        };
+        let pool = state.pool();
+        match result {
+            Ok(()) => pool.on_closed(peer.address, Instant::now()),
+            Err((ClientError::SelfConnection, _)) => pool.remove(peer.address),
+            Err((e, connected)) => {
+                debug!("{} - peer {} stopped: {}", id, peer.address, e);
+                if connected && !Self::misbehaved(&e) {
+                    pool.on_closed(peer.address, Instant::now());
+                } else {
+                    pool.on_failed(peer.address, Instant::now());
+                }
+            }
+        }
+    }
 
-            if completed_pieces % 100 == 0 {
-                persisted_file
-                    .write_pieces(
-                        &mut downloaded_file,
-                        self.torrent_file.info.piece_length as usize,
-                    )
-                    .await?;
+    //errors that are the fault of the peer, a connection that just drops is not one
+    fn misbehaved(error: &ClientError) -> bool {
+        matches!(
+            error,
+            ClientError::MalformedMessage(_)
+                | ClientError::FrameTooLarge(..)
+                | ClientError::PeerIdle(_)
+                | ClientError::PeerSnubbing(_)
+                | ClientError::UnknownProtocol
+                | ClientError::PeerIdMismatch
+        )
+    }
+
+    //incoming connections get the same treatment as the ones we open
+    async fn accept_peers(
+        mut incoming: mpsc::Receiver<IncomingPeer>,
+        state: Arc<TorrentState>,
+        pieces_done: Sender<DownloadedPiece>,
+    ) {
+        while let Some(peer) = incoming.recv().await {
+            if state.pool().is_banned(peer.address.ip()) {
+                debug!("Refused banned peer {}", peer.address);
+                continue;
             }
-            if Self::piece_hash_is_correct(&received_piece.1, pieces[received_piece.0]) {
-                info!("Received piece number: {}", received_piece.0);
-                downloaded_file.insert(received_piece.0, received_piece.1.clone());
-                completed_pieces += 1;
-            } else {
-                info!("Resend the piece to queue {} ", &received_piece.0);
-                transmitter_work.send(received_piece.0).await.unwrap();
+            let id = state.next_connection_id();
+            let t_state = Arc::clone(&state);
+            let p_pieces_done = pieces_done.clone();
+            tokio::spawn(async move {
+                let _permit = peer.permit;
+                let pool = t_state.pool();
+                pool.add(
+                    TrackerPeer {
+                        address: peer.address,
+                        peer_id: Some(*peer.handshake.peer_id()),
+                    },
+                    PeerSource::Incoming,
+                );
+                match PeerStream::accept(
+                    id,
+                    peer.address,
+                    peer.stream,
+                    peer.handshake,
+                    Arc::clone(&t_state),
+                )
+                .await
+                {
+                    Ok(mut stream) => {
+                        if let Err(e) = stream.run(&p_pieces_done).await {
+                            debug!("{} - incoming peer {} stopped: {}", id, peer.address, e);
+                        }
+                    }
+                    Err(e) => debug!("{} - incoming peer {} failed: {}", id, peer.address, e),
+                }
+                pool.on_closed(peer.address, Instant::now());
+            });
+        }
+    }
+
+    //hashes the data already in storage and saves the pieces found as resume data, so that
+    //download_torrent starts from them
+    pub async fn recheck(
+        &self,
+        progress: impl FnMut(RecheckProgress),
+    ) -> Result<Bitfield, ClientError> {
+        let supplied = self.storage.lock().unwrap().take();
+        let is_supplied = supplied.is_some();
+        if !is_supplied {
+            self.recover_move().await?;
+        }
+        let resume_file = self.resume_file(is_supplied);
+        let (mut storage, _) = self.open_storage(supplied).await?;
+        let checked = recheck(storage.as_mut(), &self.torrent_file.info, progress).await;
+        //a storage given to us is still needed by download_torrent
+        if is_supplied {
+            *self.storage.lock().unwrap() = Some(storage);
+        } else {
+            storage.close().await?;
+        }
+        let bitfield = checked?;
+        resume_file
+            .save_pieces(self.torrent_file.compute_info_hash(), &bitfield)
+            .await?;
+        Ok(bitfield)
+    }
+
+    //Moves the data and its resume file into directory without stopping the torrent. Reads and
+    //writes wait until the data is open again at the new place. Across filesystems the data is
+    //copied, and the old copy is removed only after the resume file is saved next to the new one.
+    //Data that can't be opened anywhere after a failure leaves the torrent waiting for another
+    //move, see TorrentStats::storage_error.
+    pub async fn move_storage(
+        &self,
+        directory: impl AsRef<Path>,
+        progress: impl FnMut(MoveProgress),
+    ) -> Result<(), ClientError> {
+        let (Some(state), Some(resume_file)) = (self.state.get(), self.resume.get()) else {
+            return Err(ClientError::NotMovable);
+        };
+        let directory = directory.as_ref();
+        tokio::fs::create_dir_all(directory).await?;
+        //peers and the write cache wait on the lock
+        let mut storage = state.storage().lock().await;
+        let from = self.data_path();
+        let to = directory.join(&self.torrent_file.info.name);
+        if from == to {
+            return Ok(());
+        }
+        //a skipped file that was never created has nothing to move
+        let exists = tokio::fs::try_exists(&from).await?;
+        //a copy that runs out of space halfway is refused before anything is closed
+        if exists && !same_filesystem(&from, directory).await? {
+            let size = tokio::fs::metadata(&from).await?.len();
+            let (needed, available) = disk_space(&to, size)?;
+            if needed > available {
+                return Err(ClientError::LowDiskSpace { needed, available });
             }
         }
+        //a crash from here on is picked up by the next start, see recover_move
+        let journal = MoveJournal {
+            from: from.clone(),
+            to: to.clone(),
+        };
+        if let Err(e) = journal.write().await {
+            let _ = journal.remove().await;
+            return Err(e.into());
+        }
+        if let Err(e) = storage.close().await {
+            journal.remove().await?;
+            return Err(e.into());
+        }
+        let copied = match exists {
+            true => move_file(&from, &to, progress).await,
+            false => Ok(false),
+        };
+        let copied = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                //the data is still where it was
+                self.reopen(&mut storage, &from, state).await;
+                journal.remove().await?;
+                return Err(e.into());
+            }
+        };
+        match self.open_file_storage(&to).await {
+            Ok((opened, _)) => {
+                *storage = opened;
+                state.set_storage_error(None);
+            }
+            Err(e) => {
+                //put the data back, the download goes on where it was
+                let restored = match copied {
+                    true => tokio::fs::remove_file(&to).await,
+                    false if exists => tokio::fs::rename(&to, &from).await,
+                    false => Ok(()),
+                };
+                match restored {
+                    Ok(()) => {
+                        self.reopen(&mut storage, &from, state).await;
+                        journal.remove().await?;
+                    }
+                    //the journal stays, the next start picks the data up at to
+                    Err(restore) => Self::fail_storage(&mut storage, restore.to_string(), state),
+                }
+                return Err(e);
+            }
+        }
+        *self.location.lock().unwrap() = directory.to_path_buf();
+        drop(storage);
+        resume_file.move_to(&to, vec![to.clone()], state).await?;
+        if copied {
+            tokio::fs::remove_file(&from).await?;
+        }
+        journal.remove().await?;
+        info!("Moved the data to {}", to.display());
+        Ok(())
+    }
 
+    //the data back where it was after a failed move, or the torrent stops reading and writing
+    async fn reopen(&self, storage: &mut Box<dyn Storage>, path: &Path, state: &TorrentState) {
+        match self.open_file_storage(path).await {
+            Ok((opened, _)) => *storage = opened,
+            Err(e) => Self::fail_storage(storage, e.to_string(), state),
+        }
+    }
+
+    fn fail_storage(storage: &mut Box<dyn Storage>, reason: String, state: &TorrentState) {
+        error!(
+            "Cannot open the data after a failed move, the download waits for another move: {}",
+            reason
+        );
+        *storage = Box::new(FailedStorage::new(reason.clone()));
+        state.set_storage_error(Some(reason));
+    }
+
+    //finishes or undoes a move that a crash cut short, before anything looks at the data
+    async fn recover_move(&self) -> Result<(), ClientError> {
+        if let Some(data) = MoveJournal::recover(&self.data_path()).await?
+            && let Some(directory) = data.parent()
+        {
+            info!(
+                "Picked up an interrupted move, the data is at {}",
+                data.display()
+            );
+            *self.location.lock().unwrap() = directory.to_path_buf();
+        }
         Ok(())
     }
+
+    //the file named after the torrent in the download directory
+    fn data_path(&self) -> PathBuf {
+        self.location
+            .lock()
+            .unwrap()
+            .join(&self.torrent_file.info.name)
+    }
+
+    //only the file we manage ourselves can be checked for changes
+    fn resume_file(&self, supplied_storage: bool) -> ResumeFile {
+        let data = self.data_path();
+        let files = match supplied_storage {
+            true => Vec::new(),
+            false => vec![data.clone()],
+        };
+        ResumeFile::new(data, files)
+    }
+
+    //the storage given to with_storage, or a file named after the torrent
+    async fn open_storage(
+        &self,
+        supplied: Option<Box<dyn Storage>>,
+    ) -> Result<(Box<dyn Storage>, bool), ClientError> {
+        match supplied {
+            Some(storage) => Ok((storage, true)),
+            None => self.open_file_storage(&self.data_path()).await,
+        }
+    }
+
+    //the file at path, and whether the disk has room for all of it
+    async fn open_file_storage(
+        &self,
+        path: &Path,
+    ) -> Result<(Box<dyn Storage>, bool), ClientError> {
+        let info = &self.torrent_file.info;
+        let total_size = info.total_length() as u64;
+        //a skipped file is only created if it becomes wanted
+        let skipped = (0..info.file_ranges().len())
+            .all(|file| self.config.file_priorities.get(file) == Some(&Priority::Skip));
+        if skipped && !tokio::fs::try_exists(path).await? {
+            let storage = DeferredFile::new(
+                path,
+                total_size,
+                info.piece_length,
+                self.config.storage,
+                self.config.preallocation,
+            );
+            return Ok((Box::new(storage), true));
+        }
+        let (needed, available) = disk_space(path, total_size)?;
+        let room = needed <= available;
+        if !room {
+            warn!(
+                "{} bytes still to allocate, {} available: the download pauses when the disk is full",
+                needed, available
+            );
+        }
+        let storage = open_file(
+            path,
+            total_size,
+            info.piece_length,
+            self.config.storage,
+            self.config.preallocation,
+            room,
+        )
+        .await?;
+        Ok((storage, room))
+    }
+
+    //the peers keep serving requests until the share ratio or the seeding time is reached
+    async fn seed(&self, state: &TorrentState) {
+        if self.config.seed_ratio.is_none() && self.config.seed_time.is_none() {
+            return;
+        }
+        info!("Download completed, seeding");
+        let started = Instant::now();
+        let total_length = self.torrent_file.info.total_length() as f64;
+        loop {
+            let ratio = state.uploaded() as f64 / total_length;
+            let ratio_reached = self.config.seed_ratio.is_some_and(|r| ratio >= r);
+            let time_reached = self
+                .config
+                .seed_time
+                .is_some_and(|t| started.elapsed() >= t);
+            if ratio_reached || time_reached {
+                info!(
+                    "Stop seeding, ratio {:.2} after {:?}",
+                    ratio,
+                    started.elapsed()
+                );
+                return;
+            }
+            tokio::time::sleep(SEED_CHECK_INTERVAL).await;
+        }
+    }
 }
@@ -0,0 +1,161 @@
+use crate::request::client::ClientError;
+use crate::request::handshake::Handshake;
+use crate::request::request_queue::BLOCK_LENGTH;
+use crate::request::torrent_message::TorrentMessage;
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const HANDSHAKE_LENGTH: usize = 68;
+//ut_metadata and the other extensions send at most a 16 KiB block plus a small dictionary
+const MAX_EXTENDED_LENGTH: usize = 64 * 1024;
+
+//The 68 bytes handshake, it has no length prefix so it needs its own codec.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = ClientError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Handshake>, ClientError> {
+        if src.len() < HANDSHAKE_LENGTH {
+            src.reserve(HANDSHAKE_LENGTH - src.len());
+            return Ok(None);
+        }
+        let bytes: [u8; HANDSHAKE_LENGTH] = src[..HANDSHAKE_LENGTH].try_into().unwrap();
+        src.advance(HANDSHAKE_LENGTH);
+        Handshake::parse(bytes).map(Some)
+    }
+}
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = ClientError;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> Result<(), ClientError> {
+        dst.extend_from_slice(&handshake.to_bytes());
+        Ok(())
+    }
+}
+
+//Length prefixed messages after the handshake. Every message id has a maximum length, a peer
+//announcing a bigger frame is dropped before we buffer it.
+#[derive(Debug)]
+pub struct MessageCodec {
+    number_of_pieces: usize,
+}
+
+impl MessageCodec {
+    pub fn new(number_of_pieces: usize) -> Self {
+        Self { number_of_pieces }
+    }
+
+    //id byte included
+    fn max_length(&self, id: u8) -> usize {
+        match id {
+            //choke, unchoke, interested, not interested, have all, have none
+            0..=3 | 14 | 15 => 1,
+            //have, suggest piece, allowed fast
+            4 | 13 | 17 => 5,
+            5 => 1 + self.number_of_pieces.div_ceil(8),
+            //request, cancel, reject request
+            6 | 8 | 16 => 13,
+            //we never request more than a block
+            7 => 9 + BLOCK_LENGTH as usize,
+            9 => 3,
+            _ => MAX_EXTENDED_LENGTH,
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = TorrentMessage;
+    type Error = ClientError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<TorrentMessage>, ClientError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if length == 0 {
+            src.advance(4);
+            return Ok(Some(TorrentMessage::KeepAlive));
+        }
+        let Some(&id) = src.get(4) else {
+            return Ok(None);
+        };
+        if length > self.max_length(id) {
+            return Err(ClientError::FrameTooLarge(id, length));
+        }
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+        let message = TorrentMessage::read(&src[4..4 + length]);
+        src.advance(4 + length);
+        message.map(Some)
+    }
+}
+
+impl Encoder<TorrentMessage> for MessageCodec {
+    type Error = ClientError;
+
+    fn encode(&mut self, message: TorrentMessage, dst: &mut BytesMut) -> Result<(), ClientError> {
+        dst.extend_from_slice(&message.to_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_split_across_reads_are_decoded() {
+        let mut codec = MessageCodec::new(16);
+        let have = TorrentMessage::Have { index: 7 };
+        let mut src = BytesMut::new();
+        for byte in have.to_bytes() {
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+            src.extend_from_slice(&[byte]);
+        }
+        src.extend_from_slice(&[0, 0]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(have));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(TorrentMessage::KeepAlive)
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut codec = MessageCodec::new(16);
+        let mut src = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF, 7][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(ClientError::FrameTooLarge(7, _))
+        ));
+        let mut bitfield = BytesMut::from(
+            &TorrentMessage::Bitfield {
+                bitfield: vec![0; 3],
+            }
+            .to_bytes()[..],
+        );
+        assert!(codec.decode(&mut bitfield).is_err());
+    }
+
+    #[test]
+    fn handshake_waits_for_all_bytes() {
+        let handshake = Handshake::new([1; 20], &[2; 20]);
+        let bytes = handshake.to_bytes();
+        let mut src = BytesMut::from(&bytes[..40]);
+        assert!(HandshakeCodec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&bytes[40..]);
+        src.extend_from_slice(&[0, 0, 0, 0]);
+        let decoded = HandshakeCodec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.info_hash, [1; 20]);
+        assert_eq!(&src[..], &[0, 0, 0, 0]);
+    }
+}
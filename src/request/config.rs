@@ -0,0 +1,103 @@
+use crate::request::ip_filter::IpFilter;
+use crate::request::mse::EncryptionMode;
+use crate::request::piece_picker::Priority;
+use crate::request::proxy::ProxyConfig;
+use crate::request::storage::{Preallocation, StorageBackend};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    //fixed number of block requests kept on the wire for every peer,
+    //None sizes it from the peer reqq and the measured bandwidth delay product
+    pub request_queue_depth: Option<usize>,
+    //a block is asked again only if the answer doesn't arrive within this time
+    pub block_timeout: Duration,
+    //once the download is done keep uploading until uploaded / torrent size reaches this ratio
+    pub seed_ratio: Option<f64>,
+    //once the download is done keep uploading for this long
+    pub seed_time: Option<Duration>,
+    //port where peers can connect to us, None to only open outgoing connections
+    pub listen_port: Option<u16>,
+    //incoming and outgoing peer connections open at the same time
+    pub max_connections: usize,
+    //peers unchoked for their rate at every choker round, plus one optimistic unchoke
+    pub upload_slots: usize,
+    //a keep-alive is sent when we wrote nothing to a peer for this long
+    pub keep_alive_interval: Duration,
+    //a peer that sends nothing for this long is disconnected
+    pub idle_timeout: Duration,
+    //a peer we are interested in that sends no block for this long is replaced
+    pub snub_timeout: Duration,
+    //a peer we are interested in that sends no block for this long only gets optimistic
+    //unchokes, well before snub_timeout replaces it
+    pub anti_snub_interval: Duration,
+    //peer connections the client tries to keep open, within max_connections
+    pub target_connections: usize,
+    //a peer that fails this many times in a row is banned for the session
+    pub max_peer_failures: u32,
+    //a peer that sent corrupt blocks for this many pieces is banned for the session
+    pub max_corrupt_pieces: u32,
+    //bytes per second for the whole torrent, protocol overhead included, None is unlimited
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    //bytes per second for every single peer
+    pub peer_upload_limit: Option<u64>,
+    pub peer_download_limit: Option<u64>,
+    //MSE/PE for the connections we open, and whether plain incoming connections are accepted
+    pub encryption: EncryptionMode,
+    //tracker requests and the connections we open go through it, None connects directly
+    pub proxy: Option<ProxyConfig>,
+    //uTP on the listen port and for the connections we open, TCP when the peer doesn't answer
+    pub utp: bool,
+    //addresses we neither dial, accept nor keep from the trackers, shared by the torrents
+    pub ip_filter: Arc<IpFilter>,
+    //bytes of verified pieces waiting to be written, downloading slows down past it
+    pub write_cache_size: usize,
+    //where the file named after the torrent and its resume file go, empty is the working directory
+    pub download_dir: PathBuf,
+    //how the file named after the torrent is read and written
+    pub storage: StorageBackend,
+    pub preallocation: Preallocation,
+    //by file index, files past the end are Normal
+    pub file_priorities: Vec<Priority>,
+    //download the pieces in order, for playing the data before the download ends, otherwise the
+    //pieces the fewest peers have go first
+    pub sequential: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            request_queue_depth: None,
+            block_timeout: Duration::from_secs(15),
+            seed_ratio: None,
+            seed_time: None,
+            listen_port: None,
+            max_connections: 50,
+            upload_slots: 4,
+            keep_alive_interval: Duration::from_secs(90),
+            idle_timeout: Duration::from_secs(120),
+            snub_timeout: Duration::from_secs(180),
+            anti_snub_interval: Duration::from_secs(60),
+            target_connections: 30,
+            max_peer_failures: 5,
+            max_corrupt_pieces: 2,
+            upload_limit: None,
+            download_limit: None,
+            peer_upload_limit: None,
+            peer_download_limit: None,
+            encryption: EncryptionMode::default(),
+            proxy: None,
+            utp: false,
+            ip_filter: Arc::default(),
+            write_cache_size: 32 * 1024 * 1024,
+            download_dir: PathBuf::new(),
+            storage: StorageBackend::default(),
+            preallocation: Preallocation::default(),
+            file_priorities: Vec::new(),
+            sequential: false,
+        }
+    }
+}
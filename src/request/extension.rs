@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+//BEP 10 reserved bit: byte 5, 0x10
+pub const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    //name of the extension -> id the peer wants us to use for it
+    #[serde(default)]
+    pub m: HashMap<String, u8>,
+    //number of outstanding requests the peer is able to keep in its queue
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+}
+
+impl ExtendedHandshake {
+    pub fn new(reqq: usize) -> Self {
+        Self {
+            m: HashMap::new(),
+            reqq: Some(reqq),
+            v: Some(format!("TTorrent {}", env!("CARGO_PKG_VERSION"))),
+        }
+    }
+
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        serde_bencode::from_bytes(payload).ok()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,54 @@
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
+
+//BEP 6 reserved bit: byte 7, 0x04
+pub const FAST_EXTENSION_BIT: u8 = 0x04;
+//pieces a peer may request from us while we choke it
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+//BEP 6 canonical allowed fast set, both sides of a connection compute the same pieces for a
+//given address. Only defined for IPv4.
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &[u8; 20],
+    number_of_pieces: usize,
+    size: usize,
+) -> Vec<usize> {
+    let size = size.min(number_of_pieces);
+    let mut set = Vec::with_capacity(size);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xFFFFFF00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+    while set.len() < size {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == size {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let index = (y as u64 % number_of_pieces as u64) as usize;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_bep_6_example() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+}
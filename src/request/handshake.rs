@@ -1,56 +1,210 @@
+use crate::request::client::ClientError;
+use crate::request::extension::EXTENSION_PROTOCOL_BIT;
+use crate::request::fast::FAST_EXTENSION_BIT;
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+//the first 20 bytes of a plain handshake, anything else may be an encrypted one
+pub fn is_plain_handshake(start: &[u8; 20]) -> bool {
+    start[0] as usize == PROTOCOL.len() && start[1..] == PROTOCOL[..]
+}
+
+//a feature announced with one bit of the reserved bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    //BEP 5
+    Dht,
+    //BEP 6
+    FastExtension,
+    //BEP 10
+    ExtensionProtocol,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::Dht,
+        Capability::FastExtension,
+        Capability::ExtensionProtocol,
+    ];
+
+    //byte of the reserved field and mask of the bit
+    fn position(self) -> (usize, u8) {
+        match self {
+            Capability::Dht => (7, 0x01),
+            Capability::FastExtension => (7, FAST_EXTENSION_BIT),
+            Capability::ExtensionProtocol => (5, EXTENSION_PROTOCOL_BIT),
+        }
+    }
+}
+
+//The reserved bytes of a handshake. Bits we don't know are kept, so they can still be logged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities([u8; 8]);
+
+impl Capabilities {
+    pub fn new(capabilities: &[Capability]) -> Self {
+        let mut reserved = [0u8; 8];
+        for capability in capabilities {
+            let (byte, mask) = capability.position();
+            reserved[byte] |= mask;
+        }
+        Self(reserved)
+    }
+
+    pub fn from_bytes(reserved: [u8; 8]) -> Self {
+        Self(reserved)
+    }
+
+    pub fn as_bytes(&self) -> [u8; 8] {
+        self.0
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        let (byte, mask) = capability.position();
+        self.0[byte] & mask != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.into_iter().filter(|c| self.contains(*c))
+    }
+
+    //what both sides of a connection support
+    pub fn intersection(&self, other: &Capabilities) -> Capabilities {
+        let mut reserved = self.0;
+        for (byte, other) in reserved.iter_mut().zip(other.0) {
+            *byte &= other;
+        }
+        Self(reserved)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Handshake {
     pstrlen: u8,
     pstr: [u8; 19],
-    reserved: [u8; 8],
+    reserved: Capabilities,
     pub info_hash: [u8; 20], //fixme why pub ?
     peer_id: [u8; 20],
 }
 
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: &[u8; 20]) -> Self {
-        let p = *b"BitTorrent protocol";
-        let plen = p.len() as u8;
         Self {
-            pstrlen: plen,
-            pstr: p,
-            reserved: [0; 8],
+            pstrlen: PROTOCOL.len() as u8,
+            pstr: *PROTOCOL,
+            reserved: Capabilities::new(&[
+                Capability::ExtensionProtocol,
+                Capability::FastExtension,
+            ]),
             info_hash,
-            peer_id: peer_id.clone(),
+            peer_id: *peer_id,
         }
     }
 
-    pub fn parse(input_bytes: [u8; 68]) -> Self {
+    //only the BitTorrent protocol string is accepted
+    pub fn parse(input_bytes: [u8; 68]) -> Result<Self, ClientError> {
         let pstrlen = input_bytes[0];
         let mut pstr = [0u8; 19];
         pstr.copy_from_slice(&input_bytes[1..20]);
+        if pstrlen as usize != PROTOCOL.len() || &pstr != PROTOCOL {
+            return Err(ClientError::UnknownProtocol);
+        }
         let mut reserved = [0u8; 8];
         reserved.copy_from_slice(&input_bytes[20..28]);
         let mut info_hash = [0u8; 20];
         info_hash.copy_from_slice(&input_bytes[28..48]);
         let mut peer_id = [0u8; 20];
         peer_id.copy_from_slice(&input_bytes[48..68]);
-        Self {
+        Ok(Self {
             pstrlen,
             pstr,
-            reserved,
+            reserved: Capabilities::from_bytes(reserved),
             info_hash,
             peer_id,
+        })
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.reserved
+    }
+
+    pub fn peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    //Checks the handshake of a remote peer: it has to be for our torrent, from someone else than
+    //us and, if the tracker told us, from the peer id we expect at that address.
+    pub fn validate(
+        &self,
+        info_hash: &[u8; 20],
+        our_peer_id: &[u8; 20],
+        expected_peer_id: Option<&[u8; 20]>,
+    ) -> Result<(), ClientError> {
+        if &self.info_hash != info_hash {
+            return Err(ClientError::ServerDoesntHaveFile);
         }
+        if &self.peer_id == our_peer_id {
+            return Err(ClientError::SelfConnection);
+        }
+        if expected_peer_id.is_some_and(|expected| expected != &self.peer_id) {
+            return Err(ClientError::PeerIdMismatch);
+        }
+        Ok(())
     }
 
-    pub fn to_bytes(self: Self) -> [u8; 68] {
+    pub fn to_bytes(self) -> [u8; 68] {
         let mut out = [0u8; 68];
         let mut pos = 1;
         out[0] = self.pstrlen;
         out[pos..pos + self.pstr.len()].copy_from_slice(&self.pstr);
         pos += self.pstr.len();
-        out[pos..pos + self.reserved.len()].copy_from_slice(&self.reserved);
-        pos += self.reserved.len();
+        out[pos..pos + 8].copy_from_slice(&self.reserved.as_bytes());
+        pos += 8;
         out[pos..pos + self.info_hash.len()].copy_from_slice(&self.info_hash);
         pos += self.info_hash.len();
         out[pos..pos + self.peer_id.len()].copy_from_slice(&self.peer_id);
-        pos += self.peer_id.len();
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_protocols_are_refused() {
+        let mut bytes = Handshake::new([1; 20], &[2; 20]).to_bytes();
+        assert!(Handshake::parse(bytes).is_ok());
+        bytes[1] = b'b';
+        assert!(matches!(
+            Handshake::parse(bytes),
+            Err(ClientError::UnknownProtocol)
+        ));
+    }
+
+    #[test]
+    fn reserved_bits_are_capabilities() {
+        let ours = Handshake::new([1; 20], &[2; 20]).capabilities();
+        assert!(ours.contains(Capability::ExtensionProtocol));
+        assert!(ours.contains(Capability::FastExtension));
+        assert!(!ours.contains(Capability::Dht));
+
+        let theirs = Capabilities::from_bytes([0, 0, 0, 0, 0, 0, 0, 0x01 | 0x04]);
+        let common: Vec<_> = ours.intersection(&theirs).iter().collect();
+        assert_eq!(common, vec![Capability::FastExtension]);
+    }
+
+    #[test]
+    fn self_and_unexpected_peers_are_detected() {
+        let handshake = Handshake::new([1; 20], &[2; 20]);
+        assert!(handshake.validate(&[1; 20], &[3; 20], None).is_ok());
+        assert!(matches!(
+            handshake.validate(&[1; 20], &[2; 20], None),
+            Err(ClientError::SelfConnection)
+        ));
+        assert!(matches!(
+            handshake.validate(&[1; 20], &[3; 20], Some(&[4; 20])),
+            Err(ClientError::PeerIdMismatch)
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+use log::debug;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//eMule access levels from this one up allow the range instead of blocking it
+const EMULE_ALLOW_LEVEL: u32 = 128;
+
+//where a blocked address was met
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterPoint {
+    //a connection we were about to open
+    Outbound,
+    //a connection a peer opened to us
+    Inbound,
+    //an address a tracker handed us
+    Added,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    pub ranges: usize,
+    pub blocked_outbound: u64,
+    pub blocked_inbound: u64,
+    pub blocked_added: u64,
+}
+
+//Address ranges we never talk to, loaded from eMule ipfilter.dat or PeerGuardian P2P lists.
+//Ranges are kept sorted and merged, one lookup is a binary search.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    blocked_outbound: AtomicU64,
+    blocked_inbound: AtomicU64,
+    blocked_added: AtomicU64,
+}
+
+impl IpFilter {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Ok(Self::parse(&String::from_utf8_lossy(&bytes)))
+    }
+
+    //one range per line, the format is told apart line by line, malformed lines are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut filter = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            //a P2P description may hold a comma as well
+            let range = parse_emule_line(line).or_else(|| parse_p2p_line(line));
+            match range {
+                Some(Some((start, end))) if filter.push(start, end) => (),
+                //an eMule range that is allowed
+                Some(None) => (),
+                _ => debug!("Skipping malformed ip filter line: {}", line),
+            }
+        }
+        filter.normalize();
+        filter
+    }
+
+    //start and end included, both of the same family
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) -> bool {
+        if !self.push(start, end) {
+            return false;
+        }
+        self.normalize();
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    //is_blocked, counting the address as blocked at point
+    pub fn blocks(&self, ip: IpAddr, point: FilterPoint) -> bool {
+        if !self.is_blocked(ip) {
+            return false;
+        }
+        let counter = match point {
+            FilterPoint::Outbound => &self.blocked_outbound,
+            FilterPoint::Inbound => &self.blocked_inbound,
+            FilterPoint::Added => &self.blocked_added,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            ranges: self.v4.len() + self.v6.len(),
+            blocked_outbound: self.blocked_outbound.load(Ordering::Relaxed),
+            blocked_inbound: self.blocked_inbound.load(Ordering::Relaxed),
+            blocked_added: self.blocked_added.load(Ordering::Relaxed),
+        }
+    }
+
+    fn push(&mut self, start: IpAddr, end: IpAddr) -> bool {
+        match (start.to_canonical(), end.to_canonical()) {
+            (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => {
+                self.v4.push((start.into(), end.into()));
+                true
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => {
+                self.v6.push((start.into(), end.into()));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn normalize(&mut self) {
+        merge(&mut self.v4, |ip| ip.checked_add(1));
+        merge(&mut self.v6, |ip| ip.checked_add(1));
+    }
+}
+
+//sorts the ranges and joins the ones overlapping or touching
+fn merge<T: Copy + Ord>(ranges: &mut Vec<(T, T)>, successor: fn(T) -> Option<T>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        if let Some(last) = merged.last_mut()
+            && successor(last.1).is_none_or(|next| start <= next)
+        {
+            last.1 = last.1.max(end);
+            continue;
+        }
+        merged.push((start, end));
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Copy + Ord>(ranges: &[(T, T)], ip: T) -> bool {
+    //the last range starting at or before ip is the only one that can hold it
+    let after = ranges.partition_point(|&(start, _)| start <= ip);
+    after > 0 && ip <= ranges[after - 1].1
+}
+
+//"001.002.003.000 - 001.002.003.255 , 000 , description", None if malformed and
+//Some(None) if the access level allows the range
+fn parse_emule_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    let mut fields = line.split(',');
+    let range = parse_range(fields.next()?)?;
+    let level = match fields.next() {
+        Some(level) => level.trim().parse::<u32>().ok()?,
+        None => 0,
+    };
+    Some((level < EMULE_ALLOW_LEVEL).then_some(range))
+}
+
+//"description:1.2.3.0-1.2.3.255", the description may hold colons too
+fn parse_p2p_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    line.match_indices(':')
+        .find_map(|(at, _)| parse_range(&line[at + 1..]))
+        .map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = range.split_once('-')?;
+    Some((parse_ip(start.trim())?, parse_ip(end.trim())?))
+}
+
+//IPv4 lists often pad every octet with zeros, which Ipv4Addr doesn't parse
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if ip.contains(':') {
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn loads_both_formats_and_merges_ranges() {
+        let filter = IpFilter::parse(
+            "# eMule\n\
+             001.002.003.000 - 001.002.003.255 , 000 , Some ISP\n\
+             001.002.004.000 - 001.002.004.010 , 100 , Next to it\n\
+             009.009.009.000 - 009.009.009.255 , 200 , Allowed\n\
+             2001:db8:: - 2001:db8::ffff , 000 , Documentation\n\
+             not a range , 000 , broken\n\
+             Some: company, Inc:10.0.0.0-10.0.0.255\n\
+             Overlapping:10.0.0.128-10.0.1.0\n",
+        );
+        assert_eq!(filter.stats().ranges, 3);
+        assert!(filter.is_blocked(ip("1.2.3.0")));
+        assert!(filter.is_blocked(ip("1.2.4.10")));
+        assert!(!filter.is_blocked(ip("1.2.4.11")));
+        assert!(!filter.is_blocked(ip("1.2.2.255")));
+        assert!(!filter.is_blocked(ip("9.9.9.9")));
+        assert!(filter.is_blocked(ip("10.0.1.0")));
+        assert!(filter.is_blocked(ip("::ffff:10.0.0.7")));
+        assert!(filter.is_blocked(ip("2001:db8::abcd")));
+        assert!(!filter.is_blocked(ip("2001:db8::1:0")));
+
+        assert!(filter.blocks(ip("10.0.0.1"), FilterPoint::Inbound));
+        assert!(!filter.blocks(ip("11.0.0.1"), FilterPoint::Outbound));
+        let stats = filter.stats();
+        assert_eq!((stats.blocked_inbound, stats.blocked_outbound), (1, 0));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::request::client::ClientError;
+use crate::request::codec::HANDSHAKE_LENGTH;
+use crate::request::handshake::{Handshake, is_plain_handshake};
+use crate::request::ip_filter::{FilterPoint, IpFilter};
+use crate::request::mse::{self, EncryptionMode, MseStream};
+use crate::request::peer_stream::{PeerConnection, PeerSocket};
+use crate::request::task::AbortOnDrop;
+use crate::request::utp::UtpSocket;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::time::timeout;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+//connections waiting for the torrent to pick them up
+const INCOMING_QUEUE: usize = 16;
+//pause after a failed accept, e.g. when we are out of file descriptors
+const ACCEPT_ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+//a connection whose handshake asked for one of our torrents
+pub struct IncomingPeer {
+    pub stream: PeerConnection,
+    pub address: SocketAddr,
+    pub handshake: Handshake,
+    //keep it as long as the connection is open, it counts toward the connection limit
+    pub permit: OwnedSemaphorePermit,
+}
+
+type Torrents = Arc<RwLock<HashMap<[u8; 20], mpsc::Sender<IncomingPeer>>>>;
+
+//Accepts peer connections on one port for all the registered torrents, the info hash in the
+//handshake decides which torrent gets the connection. TCP always, uTP on the same port when
+//enabled. Clones share the same sockets.
+#[derive(Clone)]
+pub struct PeerListener {
+    local_addr: SocketAddr,
+    torrents: Torrents,
+    connections: Arc<Semaphore>,
+    encryption: EncryptionMode,
+    utp: Option<Arc<UtpSocket>>,
+    _accept_tasks: Arc<Vec<AbortOnDrop>>,
+}
+
+impl PeerListener {
+    //connections is the limit shared by incoming and outgoing connections
+    pub async fn bind(
+        address: SocketAddr,
+        connections: Arc<Semaphore>,
+        encryption: EncryptionMode,
+        filter: Arc<IpFilter>,
+        utp: bool,
+    ) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents: Torrents = Arc::default();
+        let mut accept_tasks = vec![AbortOnDrop(tokio::spawn(Self::accept_loop(
+            listener,
+            Arc::clone(&torrents),
+            Arc::clone(&connections),
+            encryption,
+            Arc::clone(&filter),
+        )))];
+        let utp = if utp {
+            let socket = Arc::new(UtpSocket::bind(local_addr).await?);
+            accept_tasks.push(AbortOnDrop(tokio::spawn(Self::utp_accept_loop(
+                Arc::clone(&socket),
+                Arc::clone(&torrents),
+                Arc::clone(&connections),
+                encryption,
+                filter,
+            ))));
+            Some(socket)
+        } else {
+            None
+        };
+        info!(
+            "Listening for peers on {}, uTP: {}",
+            local_addr,
+            utp.is_some()
+        );
+        Ok(Self {
+            local_addr,
+            torrents,
+            connections,
+            encryption,
+            utp,
+            _accept_tasks: Arc::new(accept_tasks),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn connections(&self) -> Arc<Semaphore> {
+        Arc::clone(&self.connections)
+    }
+
+    pub fn encryption(&self) -> EncryptionMode {
+        self.encryption
+    }
+
+    //the uTP socket on the listen port, outgoing uTP connections can leave from it
+    pub fn utp(&self) -> Option<Arc<UtpSocket>> {
+        self.utp.clone()
+    }
+
+    //connections for info_hash are delivered to the returned receiver
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::Receiver<IncomingPeer> {
+        let (sender, receiver) = mpsc::channel(INCOMING_QUEUE);
+        self.torrents.write().unwrap().insert(info_hash, sender);
+        receiver
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.write().unwrap().remove(info_hash);
+    }
+
+    async fn accept_loop(
+        listener: TcpListener,
+        torrents: Torrents,
+        connections: Arc<Semaphore>,
+        encryption: EncryptionMode,
+        filter: Arc<IpFilter>,
+    ) {
+        loop {
+            let (stream, address) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    debug!("Cannot accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    continue;
+                }
+            };
+            Self::admit(
+                PeerSocket::Tcp(stream),
+                address,
+                &torrents,
+                &connections,
+                encryption,
+                &filter,
+            );
+        }
+    }
+
+    async fn utp_accept_loop(
+        socket: Arc<UtpSocket>,
+        torrents: Torrents,
+        connections: Arc<Semaphore>,
+        encryption: EncryptionMode,
+        filter: Arc<IpFilter>,
+    ) {
+        loop {
+            let stream = match socket.accept().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Cannot accept uTP connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_PAUSE).await;
+                    continue;
+                }
+            };
+            let address = stream.peer_addr();
+            Self::admit(
+                PeerSocket::Utp(stream),
+                address,
+                &torrents,
+                &connections,
+                encryption,
+                &filter,
+            );
+        }
+    }
+
+    //the filter and the connection limit apply to both transports
+    fn admit(
+        stream: PeerSocket,
+        address: SocketAddr,
+        torrents: &Torrents,
+        connections: &Arc<Semaphore>,
+        encryption: EncryptionMode,
+        filter: &IpFilter,
+    ) {
+        if filter.blocks(address.ip(), FilterPoint::Inbound) {
+            debug!("Refused filtered peer {}", address);
+            return;
+        }
+        //over the limit the connection is closed right away
+        let Ok(permit) = Arc::clone(connections).try_acquire_owned() else {
+            debug!("Connection limit reached, refusing {}", address);
+            return;
+        };
+        let torrents = Arc::clone(torrents);
+        tokio::spawn(async move {
+            if let Err(e) = Self::route(stream, address, permit, &torrents, encryption).await {
+                debug!("Refused incoming peer {}: {}", address, e);
+            }
+        });
+    }
+
+    async fn route(
+        stream: PeerSocket,
+        address: SocketAddr,
+        permit: OwnedSemaphorePermit,
+        torrents: &Torrents,
+        encryption: EncryptionMode,
+    ) -> Result<(), ClientError> {
+        let (stream, handshake) = timeout(
+            HANDSHAKE_TIMEOUT,
+            Self::read_handshake(stream, torrents, encryption),
+        )
+        .await??;
+        let torrent = torrents.read().unwrap().get(&handshake.info_hash).cloned();
+        let Some(torrent) = torrent else {
+            return Err(ClientError::UnknownInfoHash);
+        };
+        debug!(
+            "Incoming peer {}, encrypted: {}",
+            address,
+            stream.is_encrypted()
+        );
+        torrent
+            .send(IncomingPeer {
+                stream,
+                address,
+                handshake,
+                permit,
+            })
+            .await
+            .map_err(|_| ClientError::UnknownInfoHash)
+    }
+
+    //a plain handshake starts with the protocol string, anything else is taken for MSE
+    async fn read_handshake(
+        mut stream: PeerSocket,
+        torrents: &Torrents,
+        encryption: EncryptionMode,
+    ) -> Result<(PeerConnection, Handshake), ClientError> {
+        let mut start = [0u8; 20];
+        stream.read_exact(&mut start).await?;
+        let mut buf = [0u8; HANDSHAKE_LENGTH];
+        if is_plain_handshake(&start) {
+            if encryption == EncryptionMode::RequireEncrypted {
+                return Err(ClientError::EncryptionRequired);
+            }
+            buf[..20].copy_from_slice(&start);
+            stream.read_exact(&mut buf[20..]).await?;
+            return Ok((MseStream::plaintext(stream), Handshake::parse(buf)?));
+        }
+        let info_hashes: Vec<[u8; 20]> = torrents.read().unwrap().keys().copied().collect();
+        let (mut stream, info_hash) = mse::accept(stream, &start, &info_hashes, encryption).await?;
+        stream.read_exact(&mut buf).await?;
+        let handshake = Handshake::parse(buf)?;
+        //the keys are derived from the torrent named in the encryption handshake
+        if handshake.info_hash != info_hash {
+            return Err(ClientError::InfoHashMismatch);
+        }
+        Ok((stream, handshake))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn listener_routes_by_info_hash() {
+        let connections = Arc::new(Semaphore::new(4));
+        let listener = PeerListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            connections,
+            EncryptionMode::PreferPlaintext,
+            Arc::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let mut incoming = listener.register([1; 20]);
+
+        let mut known = TcpStream::connect(listener.local_addr()).await.unwrap();
+        known
+            .write_all(&Handshake::new([1; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        let peer = incoming.recv().await.unwrap();
+        assert_eq!(peer.handshake.info_hash, [1; 20]);
+
+        let mut unknown = TcpStream::connect(listener.local_addr()).await.unwrap();
+        unknown
+            .write_all(&Handshake::new([2; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(unknown.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn listener_tells_encrypted_handshakes_apart() {
+        let connections = Arc::new(Semaphore::new(4));
+        let listener = PeerListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            connections,
+            EncryptionMode::RequireEncrypted,
+            Arc::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        let mut incoming = listener.register([1; 20]);
+        let mut other = listener.register([2; 20]);
+
+        let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let mut encrypted = mse::connect(stream, &[1; 20], EncryptionMode::PreferEncrypted)
+            .await
+            .unwrap();
+        encrypted
+            .write_all(&Handshake::new([1; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        encrypted.flush().await.unwrap();
+        let peer = incoming.recv().await.unwrap();
+        assert_eq!(peer.handshake.info_hash, [1; 20]);
+        assert!(peer.stream.is_encrypted());
+
+        //encrypted for one torrent, the handshake asks for another
+        let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let mut mismatched = mse::connect(stream, &[1; 20], EncryptionMode::PreferEncrypted)
+            .await
+            .unwrap();
+        mismatched
+            .write_all(&Handshake::new([2; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        mismatched.flush().await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(!matches!(mismatched.read(&mut buf).await, Ok(read) if read > 0));
+        assert!(other.try_recv().is_err());
+
+        let mut plain = TcpStream::connect(listener.local_addr()).await.unwrap();
+        plain
+            .write_all(&Handshake::new([1; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        //the rest of the handshake is never read, the close may come as a reset
+        let mut buf = [0u8; 1];
+        assert!(!matches!(plain.read(&mut buf).await, Ok(read) if read > 0));
+    }
+
+    #[tokio::test]
+    async fn listener_accepts_utp_on_the_same_port() {
+        let listener = PeerListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(Semaphore::new(4)),
+            EncryptionMode::PreferPlaintext,
+            Arc::default(),
+            true,
+        )
+        .await
+        .unwrap();
+        let mut incoming = listener.register([1; 20]);
+
+        let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut stream = socket.connect(listener.local_addr()).await.unwrap();
+        stream
+            .write_all(&Handshake::new([1; 20], &[9; 20]).to_bytes())
+            .await
+            .unwrap();
+        let peer = incoming.recv().await.unwrap();
+        assert_eq!(peer.handshake.info_hash, [1; 20]);
+        assert!(matches!(peer.stream.get_ref(), PeerSocket::Utp(_)));
+    }
+
+    #[tokio::test]
+    async fn listener_refuses_filtered_addresses() {
+        let filter = Arc::new(IpFilter::parse("Loopback:127.0.0.0-127.255.255.255"));
+        let listener = PeerListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(Semaphore::new(4)),
+            EncryptionMode::PreferPlaintext,
+            Arc::clone(&filter),
+            false,
+        )
+        .await
+        .unwrap();
+        let _incoming = listener.register([1; 20]);
+
+        let mut blocked = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let _ = blocked
+            .write_all(&Handshake::new([1; 20], &[9; 20]).to_bytes())
+            .await;
+        let mut buf = [0u8; 1];
+        assert!(!matches!(blocked.read(&mut buf).await, Ok(read) if read > 0));
+        assert_eq!(filter.stats().blocked_inbound, 1);
+    }
+}
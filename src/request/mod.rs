@@ -1,5 +1,29 @@
+pub mod bitfield;
+pub mod blame;
+pub mod choker;
 pub mod client;
+pub mod codec;
+pub mod config;
+pub mod extension;
+pub mod fast;
 pub mod handshake;
+pub mod ip_filter;
+pub mod listener;
+pub mod mse;
+pub mod peer_pool;
 pub mod peer_stream;
+pub mod piece_picker;
+pub mod proxy;
+pub mod rate_limit;
+pub mod recheck;
+pub mod relocate;
+pub mod request_queue;
+pub mod resume;
+pub mod stats;
 pub mod storage;
+pub mod stream;
+pub mod task;
 pub mod torrent_message;
+pub mod torrent_state;
+pub mod utp;
+pub mod write_cache;
@@ -0,0 +1,521 @@
+use crate::request::client::ClientError;
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+
+//768 bits prime of the MSE specification, the generator is 2
+const PRIME_HEX: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+static PRIME: LazyLock<BigUint> = LazyLock::new(|| BigUint::parse_bytes(PRIME_HEX, 16).unwrap());
+const KEY_LENGTH: usize = 96;
+//the specification asks for at least 160 bits of private key
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PAD: usize = 512;
+//verification constant, eight zero bytes
+const VC: [u8; 8] = [0; 8];
+//the first bytes of the RC4 keystream are weak, both sides throw them away
+const RC4_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+//How we open and accept connections. Incoming peers can always pick either protocol unless
+//encryption is required, the mode decides what we ask for and what we select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionMode {
+    //plain BitTorrent handshake, an encrypted one if the peer hangs up on it
+    #[default]
+    PreferPlaintext,
+    //encrypted handshake offering RC4 and plaintext, a plain one if the peer doesn't speak MSE
+    PreferEncrypted,
+    //RC4 only, plain connections are refused
+    RequireEncrypted,
+}
+
+impl EncryptionMode {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionMode::RequireEncrypted => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    //what we answer to the crypto_provide of a peer
+    fn crypto_select(self, provide: u32) -> Option<u32> {
+        let rc4 = provide & CRYPTO_RC4 != 0;
+        let plaintext = provide & CRYPTO_PLAINTEXT != 0;
+        match self {
+            EncryptionMode::PreferPlaintext if plaintext => Some(CRYPTO_PLAINTEXT),
+            EncryptionMode::PreferPlaintext | EncryptionMode::PreferEncrypted if rc4 => {
+                Some(CRYPTO_RC4)
+            }
+            EncryptionMode::PreferEncrypted if plaintext => Some(CRYPTO_PLAINTEXT),
+            EncryptionMode::RequireEncrypted if rc4 => Some(CRYPTO_RC4),
+            _ => None,
+        }
+    }
+}
+
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    //MSE keys skip the first kilobyte of keystream
+    fn with_discard(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.apply(&mut [0; RC4_DISCARD]);
+        rc4
+    }
+
+    //encrypts and decrypts in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[k as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+//big endian, left padded to the 96 bytes of the prime
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0u8; PRIVATE_KEY_LENGTH];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(2u8).modpow(&private, &PRIME);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote: &[u8; KEY_LENGTH]) -> Result<[u8; KEY_LENGTH], ClientError> {
+        let remote = BigUint::from_bytes_be(remote);
+        //1 and p - 1 would give a secret anybody can guess
+        if remote <= BigUint::from(1u8) || remote >= &*PRIME - 1u8 {
+            return Err(ClientError::EncryptionFailed("invalid public key"));
+        }
+        Ok(to_key_bytes(&remote.modpow(&self.private, &PRIME)))
+    }
+}
+
+fn padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+//reads until marker, giving up after max bytes; returns nothing, the marker is consumed
+async fn synchronize<S: AsyncRead + Unpin>(
+    reader: &mut S,
+    marker: &[u8],
+    max: usize,
+) -> Result<(), ClientError> {
+    let mut window = Vec::with_capacity(max);
+    while window.len() < max {
+        window.push(reader.read_u8().await?);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+    Err(ClientError::EncryptionFailed(
+        "synchronization marker not found",
+    ))
+}
+
+//The connection after the MSE handshake. Plaintext connections go through untouched, so the
+//same type carries every peer connection.
+pub struct MseStream<S> {
+    inner: S,
+    //bytes read during the handshake and the initial payload, already decrypted
+    received: BytesMut,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    //encrypted bytes the socket didn't take yet, the keystream already moved past them
+    unsent: BytesMut,
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            received: BytesMut::new(),
+            decrypt: None,
+            encrypt: None,
+            unsent: BytesMut::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    //buffered holds the bytes read past the handshake, still as they came on the wire
+    fn new(
+        inner: S,
+        mut buffered: Vec<u8>,
+        payload: &[u8],
+        mut decrypt: Rc4,
+        encrypt: Rc4,
+        select: u32,
+    ) -> Self {
+        let encrypted = select == CRYPTO_RC4;
+        if encrypted {
+            decrypt.apply(&mut buffered);
+        }
+        let mut received = BytesMut::from(payload);
+        received.extend_from_slice(&buffered);
+        Self {
+            inner,
+            received,
+            decrypt: encrypted.then_some(decrypt),
+            encrypt: encrypted.then_some(encrypt),
+            unsent: BytesMut::new(),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_write_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.received.is_empty() {
+            let length = self.received.len().min(buf.remaining());
+            buf.put_slice(&self.received.split_to(length));
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(decrypt) = &mut self.decrypt {
+            decrypt.apply(&mut buf.filled_mut()[before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        ready!(this.poll_write_unsent(cx))?;
+        //once encrypted the bytes are ours to send, whatever the socket takes now
+        this.unsent.extend_from_slice(buf);
+        if let Some(encrypt) = &mut this.encrypt {
+            encrypt.apply(&mut this.unsent);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_write_unsent(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_unsent(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_unsent(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+//Handshake of the side that opens the connection. The plain BitTorrent handshake follows over
+//the returned stream.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    info_hash: &[u8; 20],
+    mode: EncryptionMode,
+) -> Result<MseStream<S>, ClientError> {
+    let mut stream = BufReader::new(stream);
+    let keys = KeyPair::generate();
+    stream.write_all(&keys.public).await?;
+    stream.write_all(&padding()).await?;
+    stream.flush().await?;
+
+    let mut remote = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut remote).await?;
+    let secret = keys.shared_secret(&remote)?;
+    let mut encrypt = Rc4::with_discard(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::with_discard(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&hash(&[b"req1", &secret]));
+    message.extend_from_slice(&xor(hash(&[b"req2", info_hash]), hash(&[b"req3", &secret])));
+    let start = message.len();
+    message.extend_from_slice(&VC);
+    message.extend_from_slice(&mode.crypto_provide().to_be_bytes());
+    //no padding and no initial payload, the handshake goes after
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut message[start..]);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    //the encrypted VC marks the end of the padding of the peer
+    let mut marker = VC;
+    decrypt.apply(&mut marker);
+    synchronize(&mut stream, &marker, MAX_PAD + VC.len()).await?;
+    let mut header = [0u8; 6];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    let select = u32::from_be_bytes(header[..4].try_into().unwrap());
+    let pad_length = u16::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    if select.count_ones() != 1 || select & mode.crypto_provide() == 0 {
+        return Err(ClientError::EncryptionRequired);
+    }
+    if pad_length > MAX_PAD {
+        return Err(ClientError::EncryptionFailed("padding too long"));
+    }
+    let mut pad = vec![0u8; pad_length];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    let buffered = stream.buffer().to_vec();
+    Ok(MseStream::new(
+        stream.into_inner(),
+        buffered,
+        &[],
+        decrypt,
+        encrypt,
+        select,
+    ))
+}
+
+//Handshake of the side that accepts the connection, after the first bytes showed it is not a
+//plain handshake. The info hash of the peer must be one of ours, it is returned with the stream.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    already_read: &[u8],
+    info_hashes: &[[u8; 20]],
+    mode: EncryptionMode,
+) -> Result<(MseStream<S>, [u8; 20]), ClientError> {
+    let mut stream = BufReader::new(stream);
+    let mut remote = [0u8; KEY_LENGTH];
+    let start = already_read.len().min(KEY_LENGTH);
+    remote[..start].copy_from_slice(&already_read[..start]);
+    stream.read_exact(&mut remote[start..]).await?;
+    let keys = KeyPair::generate();
+    stream.write_all(&keys.public).await?;
+    stream.write_all(&padding()).await?;
+    stream.flush().await?;
+    let secret = keys.shared_secret(&remote)?;
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD + 20).await?;
+    let mut skey = [0u8; 20];
+    stream.read_exact(&mut skey).await?;
+    let skey = xor(skey, hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == skey)
+        .ok_or(ClientError::UnknownInfoHash)?;
+    let mut decrypt = Rc4::with_discard(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::with_discard(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err(ClientError::EncryptionFailed("wrong verification constant"));
+    }
+    let provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let pad_length = u16::from_be_bytes(header[12..].try_into().unwrap()) as usize;
+    if pad_length > MAX_PAD {
+        return Err(ClientError::EncryptionFailed("padding too long"));
+    }
+    let mut pad = vec![0u8; pad_length + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let payload_length = u16::from_be_bytes(pad[pad_length..].try_into().unwrap()) as usize;
+    let mut payload = vec![0u8; payload_length];
+    stream.read_exact(&mut payload).await?;
+    decrypt.apply(&mut payload);
+
+    let select = mode
+        .crypto_select(provide)
+        .ok_or(ClientError::EncryptionRequired)?;
+    let mut message = Vec::new();
+    message.extend_from_slice(&VC);
+    message.extend_from_slice(&select.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut message);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let buffered = stream.buffer().to_vec();
+    Ok((
+        MseStream::new(
+            stream.into_inner(),
+            buffered,
+            &payload,
+            decrypt,
+            encrypt,
+            select,
+        ),
+        info_hash,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn rc4_matches_reference_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn select_follows_the_mode() {
+        let both = CRYPTO_RC4 | CRYPTO_PLAINTEXT;
+        assert_eq!(
+            EncryptionMode::PreferPlaintext.crypto_select(both),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(
+            EncryptionMode::PreferEncrypted.crypto_select(both),
+            Some(CRYPTO_RC4)
+        );
+        assert_eq!(
+            EncryptionMode::PreferEncrypted.crypto_select(CRYPTO_PLAINTEXT),
+            Some(CRYPTO_PLAINTEXT)
+        );
+        assert_eq!(
+            EncryptionMode::RequireEncrypted.crypto_select(CRYPTO_PLAINTEXT),
+            None
+        );
+    }
+
+    async fn loopback(
+        outgoing: EncryptionMode,
+        incoming: EncryptionMode,
+    ) -> (MseStream<TcpStream>, MseStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            //the listener reads the start of the handshake to tell MSE from plaintext
+            let mut first = [0u8; 20];
+            stream.read_exact(&mut first).await.unwrap();
+            accept(stream, &first, &[[1; 20], [2; 20]], incoming)
+                .await
+                .unwrap()
+        });
+        let stream = TcpStream::connect(address).await.unwrap();
+        let client = connect(stream, &[2; 20], outgoing).await.unwrap();
+        let (server, info_hash) = server.await.unwrap();
+        assert_eq!(info_hash, [2; 20]);
+        (client, server)
+    }
+
+    async fn exchange(client: &mut MseStream<TcpStream>, server: &mut MseStream<TcpStream>) {
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        client.write_all(&data).await.unwrap();
+        client.flush().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        server.write_all(b"pong").await.unwrap();
+        server.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+    }
+
+    #[tokio::test]
+    async fn encrypted_loopback() {
+        let (mut client, mut server) = loopback(
+            EncryptionMode::RequireEncrypted,
+            EncryptionMode::PreferPlaintext,
+        )
+        .await;
+        assert!(client.is_encrypted() && server.is_encrypted());
+        exchange(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn plaintext_selected_after_the_key_exchange() {
+        let (mut client, mut server) = loopback(
+            EncryptionMode::PreferEncrypted,
+            EncryptionMode::PreferPlaintext,
+        )
+        .await;
+        assert!(!client.is_encrypted() && !server.is_encrypted());
+        exchange(&mut client, &mut server).await;
+    }
+}
@@ -0,0 +1,251 @@
+use crate::parser::peers::TrackerPeer;
+use crate::request::ip_filter::{FilterPoint, IpFilter};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+//first wait after a failure, doubled at every failure in a row
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+//a peer that closed a working connection can be dialed again after this
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    //connected to us, the port is not one we can dial
+    Incoming,
+}
+
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    source: PeerSource,
+    peer_id: Option<[u8; 20]>,
+    failures: u32,
+    retry_at: Option<Instant>,
+    connected: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub known: usize,
+    pub connected: usize,
+    pub banned: usize,
+}
+
+//Every address we heard of for a torrent. The connector asks it for the next peer to dial, and
+//each connection reports how it ended so failing peers wait longer and are banned at last.
+pub struct PeerPool {
+    max_failures: u32,
+    filter: Arc<IpFilter>,
+    peers: Mutex<HashMap<SocketAddr, PeerEntry>>,
+    banned: Mutex<HashSet<IpAddr>>,
+    changed: Notify,
+}
+
+impl PeerPool {
+    pub fn new(max_failures: u32, filter: Arc<IpFilter>) -> Self {
+        Self {
+            max_failures,
+            filter,
+            peers: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashSet::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    //returns false if the address was already known, is banned or filtered
+    pub fn add(&self, peer: TrackerPeer, source: PeerSource) -> bool {
+        if self.is_banned(peer.address.ip()) {
+            return false;
+        }
+        //incoming peers went through the filter when they were accepted
+        if source != PeerSource::Incoming
+            && self.filter.blocks(peer.address.ip(), FilterPoint::Added)
+        {
+            return false;
+        }
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(&peer.address) {
+            return false;
+        }
+        peers.insert(
+            peer.address,
+            PeerEntry {
+                source,
+                peer_id: peer.peer_id,
+                failures: 0,
+                retry_at: None,
+                connected: source == PeerSource::Incoming,
+            },
+        );
+        drop(peers);
+        self.changed.notify_one();
+        true
+    }
+
+    //the next peer we can dial, it counts as connected until on_failed or on_closed
+    pub fn next_candidate(&self, now: Instant) -> Option<TrackerPeer> {
+        let banned = self.banned.lock().unwrap();
+        let mut peers = self.peers.lock().unwrap();
+        let (address, entry) = peers
+            .iter_mut()
+            .filter(|(address, entry)| {
+                entry.source != PeerSource::Incoming
+                    && !entry.connected
+                    && entry.retry_at.is_none_or(|at| at <= now)
+                    && !banned.contains(&address.ip())
+            })
+            .min_by_key(|(_, entry)| (entry.failures, entry.retry_at))?;
+        entry.connected = true;
+        Some(TrackerPeer {
+            address: *address,
+            peer_id: entry.peer_id,
+        })
+    }
+
+    //the handshake went through, the failures before don't count anymore
+    pub fn on_connected(&self, address: SocketAddr) {
+        if let Some(entry) = self.peers.lock().unwrap().get_mut(&address) {
+            entry.failures = 0;
+        }
+    }
+
+    //the peer couldn't be reached or misbehaved, the wait doubles and too many failures ban it
+    pub fn on_failed(&self, address: SocketAddr, now: Instant) {
+        let failures = {
+            let mut peers = self.peers.lock().unwrap();
+            let Some(entry) = peers.get_mut(&address) else {
+                return;
+            };
+            entry.connected = false;
+            entry.failures += 1;
+            let backoff = RETRY_BASE.saturating_mul(1 << (entry.failures - 1).min(16));
+            entry.retry_at = Some(now + backoff.min(RETRY_MAX));
+            entry.failures
+        };
+        if failures >= self.max_failures {
+            self.ban(address.ip());
+        }
+        self.changed.notify_one();
+    }
+
+    //a connection ended normally, incoming peers are forgotten since we can't dial them
+    pub fn on_closed(&self, address: SocketAddr, now: Instant) {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            match peers.get(&address).map(|entry| entry.source) {
+                Some(PeerSource::Incoming) => {
+                    peers.remove(&address);
+                }
+                Some(_) => {
+                    let entry = peers.get_mut(&address).unwrap();
+                    entry.connected = false;
+                    entry.retry_at = Some(now + RECONNECT_DELAY);
+                }
+                None => (),
+            }
+        }
+        self.changed.notify_one();
+    }
+
+    //the address is not a peer, e.g. it is ourselves
+    pub fn remove(&self, address: SocketAddr) {
+        self.peers.lock().unwrap().remove(&address);
+        self.changed.notify_one();
+    }
+
+    //banned for the rest of the session, on every port
+    pub fn ban(&self, ip: IpAddr) {
+        self.banned.lock().unwrap().insert(ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.lock().unwrap().contains(&ip)
+    }
+
+    //when the first peer waiting for a retry can be dialed
+    pub fn next_retry(&self) -> Option<Instant> {
+        let banned = self.banned.lock().unwrap();
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(address, entry)| {
+                entry.source != PeerSource::Incoming
+                    && !entry.connected
+                    && !banned.contains(&address.ip())
+            })
+            .filter_map(|(_, entry)| entry.retry_at)
+            .min()
+    }
+
+    //the addresses we can dial, incoming peers left out
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.source != PeerSource::Incoming)
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    //resolves when a peer is added or a connection ends
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let banned = self.banned.lock().unwrap().len();
+        let peers = self.peers.lock().unwrap();
+        PoolStats {
+            known: peers.len(),
+            connected: peers.values().filter(|entry| entry.connected).count(),
+            banned,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> TrackerPeer {
+        TrackerPeer {
+            address: ([10, 0, 0, 1], port).into(),
+            peer_id: None,
+        }
+    }
+
+    #[test]
+    fn failed_peers_back_off_and_get_banned() {
+        let pool = PeerPool::new(2, Arc::default());
+        pool.add(peer(1), PeerSource::Tracker);
+        let now = Instant::now();
+        let candidate = pool.next_candidate(now).unwrap();
+        assert!(pool.next_candidate(now).is_none());
+
+        pool.on_failed(candidate.address, now);
+        assert!(pool.next_candidate(now).is_none());
+        assert_eq!(pool.next_retry(), Some(now + RETRY_BASE));
+        let candidate = pool.next_candidate(now + RETRY_BASE).unwrap();
+
+        pool.on_failed(candidate.address, now);
+        assert!(pool.is_banned(candidate.address.ip()));
+        assert!(pool.next_candidate(now + RETRY_MAX).is_none());
+        assert!(!pool.add(peer(2), PeerSource::Tracker));
+    }
+
+    #[test]
+    fn incoming_peers_are_not_dialed() {
+        let pool = PeerPool::new(3, Arc::default());
+        pool.add(peer(1), PeerSource::Incoming);
+        assert!(pool.next_candidate(Instant::now()).is_none());
+        assert_eq!(pool.stats().connected, 1);
+        pool.on_closed(peer(1).address, Instant::now());
+        assert_eq!(pool.stats().known, 0);
+    }
+}
@@ -1,191 +1,870 @@
-use crate::parser::torrent_file::TorrentFile;
+use crate::parser::peers::TrackerPeer;
+use crate::request::bitfield::Bitfield;
+use crate::request::blame::DownloadedPiece;
 use crate::request::client::ClientError;
-use crate::request::client::ClientError::{HandshakeFailed, ServerDoesntHaveFile};
-use crate::request::handshake::Handshake;
+use crate::request::codec::{HANDSHAKE_LENGTH, MessageCodec};
+use crate::request::extension::{EXTENDED_HANDSHAKE_ID, ExtendedHandshake};
+use crate::request::fast::{ALLOWED_FAST_SET_SIZE, allowed_fast_set};
+use crate::request::handshake::{Capability, Handshake};
+use crate::request::ip_filter::FilterPoint;
+use crate::request::listener::HANDSHAKE_TIMEOUT;
+use crate::request::mse::{self, EncryptionMode, MseStream};
+use crate::request::rate_limit::Throttled;
+use crate::request::request_queue::{
+    BLOCK_LENGTH, BlockRequest, DEFAULT_PEER_REQQ, Received, RequestQueue,
+};
+use crate::request::stats::PeerStats;
 use crate::request::torrent_message::TorrentMessage;
+use crate::request::torrent_state::TorrentState;
+use crate::request::utp::UtpStream;
+use async_channel::Sender;
+use futures::{SinkExt, StreamExt};
 use log::debug;
-use std::collections::HashSet;
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tokio::time::timeout;
+use tokio_util::codec::{FramedRead, FramedWrite};
 
-const PAYLOAD_LENGTH: u32 = 16384;
-const MAX_REQUEST_FOR_PIECE: usize = 20;
+//messages waiting for the writer task, past this a peer that doesn't read slows us down
+const WRITE_QUEUE: usize = 256;
+//bigger requests are refused, most clients never ask more than BLOCK_LENGTH
+const MAX_UPLOAD_REQUEST: u32 = 8 * BLOCK_LENGTH;
+//Suggest Piece and Allowed Fast we remember, older suggestions are forgotten
+const MAX_FAST_HINTS: usize = 32;
+//a peer that doesn't answer the uTP SYN by then is dialed over TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
 
-pub struct PeerStream {
+//Anything a peer connection can run over: TCP, MSE, uTP, a proxy or an in-memory pipe.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+//A socket to a peer, TCP or uTP, under the encryption of MseStream.
+pub enum PeerSocket {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for PeerSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerSocket::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerSocket::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerSocket::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerSocket::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+//the connections we dial and accept, plain or encrypted
+pub type PeerConnection = MseStream<PeerSocket>;
+
+pub struct PeerStream<S: Transport> {
     id: usize,
-    stream: TcpStream,
-    piece_length: usize,
-    bitfield: TorrentMessage,
+    address: SocketAddr,
+    reader: FramedRead<Throttled<ReadHalf<S>>, MessageCodec>,
+    //the write half lives in its own task, so sending never waits on receiving
+    writer: mpsc::Sender<TorrentMessage>,
+    state: Arc<TorrentState>,
+    bitfield: Bitfield,
+    //pieces the peer knows we have, from our bitfield and the Have sent since then
+    announced: Bitfield,
+    choked: bool,
+    interested: bool,
+    am_choking: bool,
+    stats: Arc<PeerStats>,
+    //both sides set the BEP 6 bit in the handshake
+    fast: bool,
+    //pieces the peer lets us download while it chokes us
+    allowed_fast: Vec<usize>,
+    suggested: Vec<usize>,
+    //pieces the peer can download while we choke it
+    allowed_for_peer: Vec<usize>,
+    queue: RequestQueue,
+    last_received: Instant,
+    last_sent: Instant,
+    //last block, or when we became interested, to tell a snubbing peer
+    last_useful: Instant,
 }
 
-impl PeerStream {
+impl PeerStream<PeerConnection> {
     pub async fn new(
         id: usize,
-        peer: &SocketAddr,
-        torrent_file: &TorrentFile,
-        client_peer_id: &[u8; 20],
+        peer: &TrackerPeer,
+        state: Arc<TorrentState>,
     ) -> Result<Self, ClientError> {
-        //create connection to peer
-        let mut stream = timeout(Duration::from_secs(5), TcpStream::connect(peer)).await??;
-        //handshake
-        let handshake = Handshake::new(torrent_file.compute_info_hash(), client_peer_id);
-        Self::make_handshake(&mut stream, &handshake).await?;
-
-        //looping until we saw a bitfield and we are unchoked
-        let mut bitfield: Option<TorrentMessage> = None;
-        let mut chocked: Option<bool> = None;
-        loop {
-            if bitfield.is_some() && chocked.is_some() {
-                debug!("Ready for download from peer: {:?}", peer);
-                return Ok(Self {
-                    id: id,
-                    stream: stream,
-                    piece_length: torrent_file.info.piece_length as usize,
-                    bitfield: bitfield.unwrap(),
-                });
-            }
-            match Self::read_message(&mut stream).await? {
-                msg => match msg {
-                    TorrentMessage::Bitfield { .. } => {
-                        bitfield = Some(msg);
-                    }
-                    TorrentMessage::Unchoke => {
-                        chocked = Some(false);
-                    }
-                    _ => continue,
-                },
-            };
+        let ip = peer.address.ip();
+        if state.config().ip_filter.blocks(ip, FilterPoint::Outbound) {
+            return Err(ClientError::Blocked(ip));
+        }
+        let encryption = state.config().encryption;
+        let encrypted = encryption != EncryptionMode::PreferPlaintext;
+        let stream = Self::dial(peer, &state).await?;
+        match Self::open(id, peer, stream, Arc::clone(&state), encrypted).await {
+            //the peer may only speak the other protocol, it gets one more connection with it
+            Err(e) if encryption != EncryptionMode::RequireEncrypted && Self::refused(&e) => {
+                debug!(
+                    "{} - handshake failed ({}), retrying encrypted: {}",
+                    id, e, !encrypted
+                );
+                let stream = Self::dial(peer, &state).await?;
+                Self::open(id, peer, stream, state, !encrypted).await
+            }
+            result => result,
         }
     }
 
-    pub async fn download_piece(
-        &mut self,
-        piece_id: usize,
-    ) -> Result<(usize, Vec<u8>), ClientError> {
-        if !self.bitfield.source_has_piece(piece_id) {
-            return Err(ClientError::PieceNotPresent(piece_id));
-        }
-
-        let total_request_to_do =
-            (self.piece_length as f32 / PAYLOAD_LENGTH as f32).ceil() as usize;
-        let mut downloaded_blocks: Vec<Option<Vec<u8>>> = vec![None; total_request_to_do];
-        let mut missing_block: HashSet<usize> = HashSet::with_capacity(total_request_to_do);
-        let mut chocked: bool = false;
-        missing_block.extend(0..total_request_to_do);
+    //uTP first when it is on, a peer that doesn't answer it is dialed over TCP. Both go through
+    //the proxy when there is one.
+    async fn dial(peer: &TrackerPeer, state: &TorrentState) -> Result<PeerSocket, ClientError> {
+        if let Some(utp) = state.utp_socket() {
+            match timeout(UTP_CONNECT_TIMEOUT, utp.connect(peer.address)).await {
+                Ok(Ok(stream)) => return Ok(PeerSocket::Utp(stream)),
+                _ => debug!("{:?} doesn't answer over uTP, trying TCP", peer.address),
+            }
+        }
+        let connecting = async {
+            match &state.config().proxy {
+                Some(proxy) => proxy.connect(peer.address).await,
+                None => Ok(TcpStream::connect(peer.address).await?),
+            }
+        };
+        let stream = timeout(Duration::from_secs(5), connecting).await??;
+        Ok(PeerSocket::Tcp(stream))
+    }
+
+    //how a peer hangs up on a protocol it doesn't want
+    fn refused(error: &ClientError) -> bool {
+        matches!(
+            error,
+            ClientError::Io(_)
+                | ClientError::Timeout
+                | ClientError::HandshakeFailed
+                | ClientError::EncryptionFailed(_)
+                | ClientError::EncryptionRequired
+        )
+    }
+
+    async fn open(
+        id: usize,
+        peer: &TrackerPeer,
+        stream: PeerSocket,
+        state: Arc<TorrentState>,
+        encrypted: bool,
+    ) -> Result<Self, ClientError> {
+        let stream = if encrypted {
+            let encryption = state.config().encryption;
+            let info_hash = state.info_hash();
+            timeout(
+                HANDSHAKE_TIMEOUT,
+                mse::connect(stream, &info_hash, encryption),
+            )
+            .await??
+        } else {
+            MseStream::plaintext(stream)
+        };
+        debug!(
+            "{} - opened {:?}, encrypted: {}",
+            id,
+            peer.address,
+            stream.is_encrypted()
+        );
+        Self::connect(id, peer, stream, state).await
+    }
+}
+
+impl<S: Transport> PeerStream<S> {
+    //handshake as the side that opened the connection
+    pub async fn connect(
+        id: usize,
+        peer: &TrackerPeer,
+        mut stream: S,
+        state: Arc<TorrentState>,
+    ) -> Result<Self, ClientError> {
+        let handshake = Handshake::new(state.info_hash(), state.client_peer_id());
+        stream.write_all(&handshake.to_bytes()).await?;
+        stream.flush().await?;
+        //exactly the handshake, what the peer sent after it is left to the message reader
+        let mut buf = [0u8; HANDSHAKE_LENGTH];
+        timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut buf)).await??;
+        let received_handshake = Handshake::parse(buf)?;
+        received_handshake.validate(
+            &handshake.info_hash,
+            state.client_peer_id(),
+            peer.peer_id.as_ref(),
+        )?;
+        debug!("Connected to peer: {:?}", peer.address);
+        Self::start(id, peer.address, stream, received_handshake, state).await
+    }
+
+    //the listener already read the handshake of the peer and matched the info hash
+    pub async fn accept(
+        id: usize,
+        address: SocketAddr,
+        mut stream: S,
+        received_handshake: Handshake,
+        state: Arc<TorrentState>,
+    ) -> Result<Self, ClientError> {
+        received_handshake.validate(&state.info_hash(), state.client_peer_id(), None)?;
+        let handshake = Handshake::new(state.info_hash(), state.client_peer_id());
+        stream.write_all(&handshake.to_bytes()).await?;
+        stream.flush().await?;
+        Self::start(id, address, stream, received_handshake, state).await
+    }
+
+    async fn start(
+        id: usize,
+        address: SocketAddr,
+        stream: S,
+        received_handshake: Handshake,
+        state: Arc<TorrentState>,
+    ) -> Result<Self, ClientError> {
+        let stats = state.register_peer(id, address, *received_handshake.peer_id())?;
+        let number_of_pieces = state.info().number_of_pieces();
+        let (read_half, write_half) = tokio::io::split(stream);
+        //every byte on the wire counts against the client, torrent and peer limits
+        let download = vec![
+            Arc::clone(&state.global_limits().download),
+            Arc::clone(&state.limits().download),
+            Arc::clone(&stats.limits().download),
+        ];
+        let upload = vec![
+            Arc::clone(&state.global_limits().upload),
+            Arc::clone(&state.limits().upload),
+            Arc::clone(&stats.limits().upload),
+        ];
+        let read_half = Throttled::new(read_half, download);
+        let write_half = Throttled::new(write_half, upload);
+        let reader = FramedRead::new(read_half, MessageCodec::new(number_of_pieces));
+        let (writer, outgoing) = mpsc::channel(WRITE_QUEUE);
+        let framed_writer = FramedWrite::new(write_half, MessageCodec::new(number_of_pieces));
+        tokio::spawn(async move {
+            if let Err(e) = Self::write_loop(framed_writer, outgoing).await {
+                debug!("{} - cannot write to peer: {}", id, e);
+            }
+        });
+
+        let capabilities = Handshake::new(state.info_hash(), state.client_peer_id())
+            .capabilities()
+            .intersection(&received_handshake.capabilities());
+        debug!(
+            "{} - capabilities {:?}",
+            id,
+            capabilities.iter().collect::<Vec<_>>()
+        );
+        let fast = capabilities.contains(Capability::FastExtension);
+        let announced = state.bitfield();
+        let allowed_for_peer = match address.ip().to_canonical() {
+            IpAddr::V4(ip) if fast => allowed_fast_set(
+                ip,
+                &state.info_hash(),
+                number_of_pieces,
+                ALLOWED_FAST_SET_SIZE,
+            ),
+            _ => Vec::new(),
+        };
+        let config = state.config();
+        let queue = RequestQueue::new(config.request_queue_depth, config.block_timeout);
+        let mut peer = Self {
+            id,
+            address,
+            reader,
+            writer,
+            bitfield: Bitfield::new(number_of_pieces),
+            state,
+            announced,
+            choked: true,
+            interested: false,
+            am_choking: true,
+            stats,
+            fast,
+            allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            allowed_for_peer,
+            queue,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            last_useful: Instant::now(),
+        };
+
+        if capabilities.contains(Capability::ExtensionProtocol) {
+            peer.send(TorrentMessage::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: ExtendedHandshake::new(DEFAULT_PEER_REQQ).to_bytes(),
+            })
+            .await?;
+        }
+        //the bitfield can only be sent right after the handshake
+        let have = if fast && peer.announced.is_complete() {
+            Some(TorrentMessage::HaveAll)
+        } else if fast && peer.announced.count() == 0 {
+            Some(TorrentMessage::HaveNone)
+        } else if peer.announced.count() > 0 {
+            Some(TorrentMessage::Bitfield {
+                bitfield: peer.announced.as_bytes().to_vec(),
+            })
+        } else {
+            None
+        };
+        if let Some(have) = have {
+            peer.send(have).await?;
+        }
+        for index in peer.allowed_for_peer.clone() {
+            peer.send(TorrentMessage::AllowedFast {
+                index: index as u32,
+            })
+            .await?;
+        }
+        Ok(peer)
+    }
+
+    //messages queued together are written with a single flush
+    async fn write_loop(
+        mut writer: FramedWrite<Throttled<WriteHalf<S>>, MessageCodec>,
+        mut outgoing: mpsc::Receiver<TorrentMessage>,
+    ) -> Result<(), ClientError> {
+        while let Some(msg) = outgoing.recv().await {
+            writer.feed(msg).await?;
+            while let Ok(msg) = outgoing.try_recv() {
+                writer.feed(msg).await?;
+            }
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    //Downloads pieces taken from the picker and serves the requests of the peer until the
+    //connection drops. Pieces that are still incomplete when the peer goes away are given back.
+    pub async fn run(&mut self, pieces_done: &Sender<DownloadedPiece>) -> Result<(), ClientError> {
+        let result = self.run_loop(pieces_done).await;
+        for (piece_id, blocks) in self.queue.take_unfinished() {
+            self.state.give_back(piece_id, blocks);
+        }
+        result
+    }
+
+    async fn run_loop(&mut self, pieces_done: &Sender<DownloadedPiece>) -> Result<(), ClientError> {
+        let state = Arc::clone(&self.state);
+        let mut completed_pieces = state.subscribe();
+        let mut choke_decision = self.stats.subscribe_choke();
         loop {
-            if missing_block.is_empty() {
-                return Ok((
+            self.check_timers().await?;
+            while self.queue.needs_pieces() {
+                let Some(piece_id) = self.pick_piece() else {
+                    break;
+                };
+                let blocks = self.state.take_blocks(piece_id);
+                self.queue.add_partial_piece(
                     piece_id,
-                    Self::build_piece_from_blocks(self, &mut downloaded_blocks),
-                ));
-            }
-            if !chocked {
-                Self::make_request_for_block(&mut self.stream, piece_id, &mut missing_block)
-                    .await?;
+                    self.state.info().piece_size(piece_id),
+                    blocks,
+                );
             }
+            self.send_requests().await?;
 
-            let _ = tokio::time::timeout(Duration::from_millis(1500), async {
-                loop {
-                    if missing_block.is_empty() {
-                        break;
-                    }
-                    match Self::read_message(&mut self.stream).await {
-                        Ok(msg) => match msg {
-                            TorrentMessage::Piece {
-                                index,
-                                begin,
-                                block,
-                            } => {
-                                debug!(
-                                    "{} - received piece.. index:{:?}, beign: {:?}",
-                                    self.id, index, begin
-                                );
-                                let block_index = (begin as usize) / (PAYLOAD_LENGTH as usize);
-                                let was_present = missing_block.remove(&block_index);
-                                if was_present {
-                                    downloaded_blocks[block_index] = Some(block);
-                                }
-                            }
-                            TorrentMessage::KeepAlive => (),
-                            TorrentMessage::Bitfield { .. } => (),
-                            TorrentMessage::Choke => chocked = true,
-                            TorrentMessage::Unchoke => chocked = false,
-                            _ => {}
-                        },
-                        Err(_e) => (), //fixme dovrebbe ritornare un errore
+            //when the timeout elapses some block is late, the next iteration asks it again
+            let wait = self.queue.next_deadline(Instant::now());
+            let wants_work = self.interested && self.queue.is_empty();
+            tokio::select! {
+                msg = timeout(wait, self.read_message()) => {
+                    if let Ok(msg) = msg {
+                        self.last_received = Instant::now();
+                        self.handle_message(msg?, pieces_done).await?;
                     }
                 }
-            })
-            .await;
+                Ok(()) = completed_pieces.changed() => self.announce_new_pieces().await?,
+                Ok(()) = choke_decision.changed() => {
+                    let choke = *choke_decision.borrow_and_update();
+                    self.set_choking(choke).await?
+                }
+                //another peer gave a piece back, maybe this one has it
+                _ = state.picker().wait(), if wants_work => {}
+            }
         }
     }
 
-    async fn read_message(stream: &mut TcpStream) -> Result<TorrentMessage, ClientError> {
-        let mut init_buf = [0u8; 4];
-        stream
-            .read_exact(&mut init_buf)
-            .await
-            .map_err(|_| ClientError::NoBytesInStream)?;
-        let message_length = u32::from_be_bytes(init_buf) as usize;
-        let mut message_buf = vec![0u8; message_length];
-        stream
-            .read_exact(&mut message_buf)
-            .await
-            .map_err(|_| ClientError::NoBytesInStream)?;
-        Ok(TorrentMessage::read(&message_buf))
+    //The loop wakes up at least once a second, so these run often enough. A dead peer is dropped,
+    //and so is one that gives us nothing we ask for: its permit lets another peer connect.
+    async fn check_timers(&mut self) -> Result<(), ClientError> {
+        let now = Instant::now();
+        if self.state.pool().is_banned(self.address.ip()) {
+            return Err(ClientError::PeerBanned);
+        }
+        let config = self.state.config();
+        let idle = now.duration_since(self.last_received);
+        if idle >= config.idle_timeout {
+            return Err(ClientError::PeerIdle(idle));
+        }
+        let useless = now.duration_since(self.last_useful);
+        if self.interested && useless >= config.snub_timeout {
+            return Err(ClientError::PeerSnubbing(useless));
+        }
+        if now.duration_since(self.last_sent) >= config.keep_alive_interval {
+            self.send(TorrentMessage::KeepAlive).await?;
+        }
+        Ok(())
     }
 
-    async fn make_handshake(
-        stream: &mut TcpStream,
-        handshake: &Handshake,
-    ) -> Result<(), ClientError> {
-        let data = handshake.to_bytes();
-        stream.write_all(&data).await?;
-        let mut buf = [0u8; 68];
-        let n = stream.read(&mut buf).await?;
-        if n > 0 {
-            let received_handshake = Handshake::parse(buf);
-            if received_handshake.info_hash != handshake.info_hash {
-                return Err(ServerDoesntHaveFile);
-            }
-            Ok(())
+    //while choked only the allowed fast pieces can be requested
+    fn pick_piece(&self) -> Option<usize> {
+        let picker = self.state.picker();
+        if !self.interested {
+            None
+        } else if !self.choked {
+            picker.pick(self.address.ip(), &self.bitfield, &self.suggested)
+        } else if self.fast {
+            picker.pick_among(self.address.ip(), &self.bitfield, &self.allowed_fast)
         } else {
-            Err(HandshakeFailed)
+            None
+        }
+    }
+
+    async fn send_requests(&mut self) -> Result<(), ClientError> {
+        if self.choked && !self.fast {
+            return Ok(());
+        }
+        let choked = self.choked;
+        let allowed_fast = &self.allowed_fast;
+        let requests = self.queue.next_requests_among(Instant::now(), |index| {
+            !choked || allowed_fast.contains(&(index as usize))
+        });
+        for request in requests {
+            self.send(request.to_message()).await?;
         }
+        Ok(())
     }
-    async fn make_request_for_block(
-        stream: &mut TcpStream,
-        index: usize,
-        missing_block: &mut HashSet<usize>,
+
+    async fn handle_message(
+        &mut self,
+        msg: TorrentMessage,
+        pieces_done: &Sender<DownloadedPiece>,
     ) -> Result<(), ClientError> {
-        for block in missing_block.iter().take(MAX_REQUEST_FOR_PIECE) {
-            let request = TorrentMessage::Request {
-                index: index as u32,
-                begin: (*block * PAYLOAD_LENGTH as usize) as u32,
-                length: PAYLOAD_LENGTH,
-            }
-            .to_bytes();
-            let write = stream.write_all(&request).await;
-            match write {
-                Ok(_) => (),
-                Err(e) => {
-                    return Err(ClientError::Io(e));
+        match msg {
+            TorrentMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                debug!(
+                    "{} - received piece.. index:{:?}, beign: {:?}",
+                    self.id, index, begin
+                );
+                let length = block.len() as u64;
+                let received =
+                    self.queue
+                        .on_block(index, begin, block, self.address.ip(), Instant::now());
+                //a block nobody asked for doesn't make the peer useful
+                if received != Received::Dropped {
+                    self.stats.add_downloaded(length);
+                    self.last_useful = Instant::now();
+                }
+                if let Received::Completed(piece) = received {
+                    pieces_done
+                        .send(piece)
+                        .await
+                        .map_err(|_| ClientError::ChannelReceiverError)?;
+                }
+            }
+            TorrentMessage::Request {
+                index,
+                begin,
+                length,
+            } => self.serve_request(index, begin, length).await?,
+            TorrentMessage::Choke => {
+                self.choked = true;
+                //with the fast extension every request dropped by the peer gets a Reject
+                if !self.fast {
+                    self.queue.on_choke();
+                }
+            }
+            TorrentMessage::Unchoke => self.choked = false,
+            //the choker decides if an interested peer gets unchoked
+            TorrentMessage::Interested => self.stats.set_peer_interested(true),
+            TorrentMessage::NotInterested => self.stats.set_peer_interested(false),
+            TorrentMessage::Bitfield { bitfield } => {
+                self.set_bitfield(Bitfield::from_bytes(
+                    &bitfield,
+                    self.state.info().number_of_pieces(),
+                ));
+                self.update_interest().await?;
+            }
+            TorrentMessage::Have { index } => {
+                if !self.bitfield.has(index as usize) {
+                    self.bitfield.set(index as usize);
+                    self.state.picker().add_availability([index as usize]);
+                }
+                self.update_interest().await?;
+            }
+            TorrentMessage::HaveAll => {
+                self.set_bitfield(Bitfield::full(self.state.info().number_of_pieces()));
+                self.update_interest().await?;
+            }
+            TorrentMessage::HaveNone => {
+                self.set_bitfield(Bitfield::new(self.state.info().number_of_pieces()));
+                self.update_interest().await?;
+            }
+            TorrentMessage::SuggestPiece { index } => {
+                Self::remember(&mut self.suggested, index as usize);
+            }
+            TorrentMessage::AllowedFast { index } => {
+                Self::remember(&mut self.allowed_fast, index as usize);
+            }
+            TorrentMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => self.on_reject(index, begin, length),
+            TorrentMessage::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } => {
+                if let Some(reqq) = ExtendedHandshake::parse(&payload).and_then(|h| h.reqq) {
+                    debug!("{} - peer reqq {}", self.id, reqq);
+                    self.queue.set_peer_reqq(reqq);
                 }
             }
+            _ => (),
         }
         Ok(())
     }
 
-    fn build_piece_from_blocks(&self, downloaded_blocks: &mut Vec<Option<Vec<u8>>>) -> Vec<u8> {
-        let mut final_piece = Vec::with_capacity(self.piece_length);
-        for block_opt in downloaded_blocks {
-            if let Some(block_data) = block_opt {
-                final_piece.extend_from_slice(&block_data);
+    //the picker counts the pieces of every peer for rarest first
+    fn set_bitfield(&mut self, bitfield: Bitfield) {
+        let picker = self.state.picker();
+        picker.remove_availability(self.bitfield.pieces());
+        picker.add_availability(bitfield.pieces());
+        self.bitfield = bitfield;
+    }
+
+    //a hint from the peer, the newest ones are kept
+    fn remember(hints: &mut Vec<usize>, index: usize) {
+        if hints.contains(&index) {
+            return;
+        }
+        if hints.len() == MAX_FAST_HINTS {
+            hints.remove(0);
+        }
+        hints.push(index);
+    }
+
+    fn on_reject(&mut self, index: u32, begin: u32, length: u32) {
+        debug!(
+            "{} - request rejected index:{}, begin:{}, length:{}",
+            self.id, index, begin, length
+        );
+        if self.choked {
+            //the block waits for the unchoke, asking again now would be rejected as well
+            self.allowed_fast.retain(|piece| *piece != index as usize);
+            self.queue.on_reject(BlockRequest {
+                index,
+                begin,
+                length,
+            });
+        } else if let Some(blocks) = self.queue.abandon_piece(index) {
+            //the peer won't send this piece, as if it didn't have it
+            if self.bitfield.has(index as usize) {
+                self.bitfield.unset(index as usize);
+                self.state.picker().remove_availability([index as usize]);
+            }
+            self.state.give_back(index as usize, blocks);
+        }
+    }
+
+    async fn serve_request(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<(), ClientError> {
+        let piece_size = self.state.info().piece_size(index as usize) as u64;
+        let allowed_fast = self.allowed_for_peer.contains(&(index as usize));
+        if (self.am_choking && !allowed_fast)
+            || length > MAX_UPLOAD_REQUEST
+            || begin as u64 + length as u64 > piece_size
+            || !self.state.has_piece(index as usize)
+        {
+            debug!(
+                "{} - refused request index:{}, begin:{}, length:{}",
+                self.id, index, begin, length
+            );
+            if self.fast {
+                self.send(TorrentMessage::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+            }
+            return Ok(());
+        }
+        let block = self
+            .state
+            .storage()
+            .lock()
+            .await
+            .read_block(index as usize, begin as usize, length as usize)
+            .await?;
+        self.send(TorrentMessage::Piece {
+            index,
+            begin,
+            block,
+        })
+        .await?;
+        self.state.add_uploaded(length as u64);
+        self.stats.add_uploaded(length as u64);
+        Ok(())
+    }
+
+    //tells the peer about the pieces we completed since the last time
+    async fn announce_new_pieces(&mut self) -> Result<(), ClientError> {
+        let ours = self.state.bitfield();
+        for index in ours.difference(&self.announced) {
+            self.send(TorrentMessage::Have {
+                index: index as u32,
+            })
+            .await?;
+        }
+        self.announced = ours;
+        self.update_interest().await
+    }
+
+    //we are interested as long as the peer has something we are missing
+    async fn update_interest(&mut self) -> Result<(), ClientError> {
+        let interested = self.state.bitfield().is_interested_in(&self.bitfield);
+        if interested != self.interested {
+            self.interested = interested;
+            if interested {
+                self.last_useful = Instant::now();
             }
+            self.stats.set_am_interested(interested);
+            let msg = if interested {
+                TorrentMessage::Interested
+            } else {
+                TorrentMessage::NotInterested
+            };
+            self.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_choking(&mut self, choke: bool) -> Result<(), ClientError> {
+        if choke == self.am_choking {
+            return Ok(());
+        }
+        self.am_choking = choke;
+        let msg = if choke {
+            TorrentMessage::Choke
+        } else {
+            TorrentMessage::Unchoke
+        };
+        self.send(msg).await
+    }
+
+    async fn send(&mut self, msg: TorrentMessage) -> Result<(), ClientError> {
+        self.last_sent = Instant::now();
+        //the writer task stops when the connection breaks
+        self.writer
+            .send(msg)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+
+    //the codec keeps partial frames between calls, so it can be raced against timeouts
+    async fn read_message(&mut self) -> Result<TorrentMessage, ClientError> {
+        self.reader
+            .next()
+            .await
+            .unwrap_or(Err(ClientError::NoBytesInStream))
+    }
+}
+
+impl<S: Transport> Drop for PeerStream<S> {
+    fn drop(&mut self) {
+        self.state.unregister_peer(self.id);
+        self.state
+            .picker()
+            .remove_availability(self.bitfield.pieces());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::torrent_file::TorrentFile;
+    use crate::request::config::ClientConfig;
+    use crate::request::rate_limit::BandwidthLimits;
+    use crate::request::storage::MemoryStorage;
+    use crate::request::task::AbortOnDrop;
+    use crate::request::utp::UtpSocket;
+    use sha1::{Digest, Sha1};
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_LENGTH as usize;
+
+    fn torrent(data: &[u8]) -> TorrentFile {
+        let pieces: Vec<u8> = data
+            .chunks(PIECE_LENGTH)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut bencode = format!(
+            "d4:infod6:lengthi{}e4:name4:test12:piece lengthi{}e6:pieces{}:",
+            data.len(),
+            PIECE_LENGTH,
+            pieces.len()
+        )
+        .into_bytes();
+        bencode.extend_from_slice(&pieces);
+        bencode.extend_from_slice(b"ee");
+        serde_bencode::from_bytes(&bencode).unwrap()
+    }
+
+    fn state(torrent: &TorrentFile, peer_id: u8, data: Option<&[u8]>) -> Arc<TorrentState> {
+        let number_of_pieces = torrent.info.number_of_pieces();
+        let (bitfield, storage) = match data {
+            Some(data) => (
+                Bitfield::full(number_of_pieces),
+                MemoryStorage::with_data(data.to_vec(), PIECE_LENGTH),
+            ),
+            None => (
+                Bitfield::new(number_of_pieces),
+                MemoryStorage::new(torrent.info.total_length(), PIECE_LENGTH),
+            ),
+        };
+        Arc::new(TorrentState::new(
+            torrent.clone(),
+            [peer_id; 20],
+            ClientConfig::default(),
+            bitfield,
+            Box::new(storage),
+            BandwidthLimits::unlimited(),
+        ))
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..3 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect()
+    }
+
+    //the seeder lets the leecher take every piece through the allowed fast set
+    async fn download_over<S: Transport>(ours: S, mut theirs: S) {
+        let data = test_data();
+        let torrent = torrent(&data);
+        let seeder = state(&torrent, 1, Some(&data));
+        let leecher = state(&torrent, 2, None);
+
+        let seeding = AbortOnDrop(tokio::spawn(async move {
+            let mut buf = [0u8; HANDSHAKE_LENGTH];
+            theirs.read_exact(&mut buf).await.unwrap();
+            let handshake = Handshake::parse(buf).unwrap();
+            let address = ([10, 0, 0, 2], 6881).into();
+            let mut peer = PeerStream::accept(1, address, theirs, handshake, seeder)
+                .await
+                .unwrap();
+            let (done, _) = async_channel::unbounded();
+            let _ = peer.run(&done).await;
+        }));
+        let (done, pieces) = async_channel::unbounded();
+        let leeching = AbortOnDrop(tokio::spawn(async move {
+            let seeder = TrackerPeer {
+                address: ([10, 0, 0, 1], 6881).into(),
+                peer_id: Some([1; 20]),
+            };
+            let mut peer = PeerStream::connect(1, &seeder, ours, leecher)
+                .await
+                .unwrap();
+            let _ = peer.run(&done).await;
+        }));
+
+        for _ in 0..3 {
+            let piece = timeout(Duration::from_secs(10), pieces.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            let start = piece.index * PIECE_LENGTH;
+            assert!(piece.data == data[start..start + PIECE_LENGTH]);
         }
-        final_piece
+        drop((seeding, leeching));
+    }
+
+    #[tokio::test]
+    async fn download_over_duplex() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        download_over(ours, theirs).await;
+    }
+
+    #[tokio::test]
+    async fn download_over_utp() {
+        let ours = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let theirs = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (outgoing, incoming) =
+            tokio::join!(ours.connect(theirs.local_addr().unwrap()), theirs.accept());
+        let (outgoing, incoming) = (outgoing.unwrap(), incoming.unwrap());
+        download_over(
+            MseStream::plaintext(PeerSocket::Utp(outgoing)),
+            MseStream::plaintext(PeerSocket::Utp(incoming)),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn download_over_encrypted_duplex() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let info_hash = torrent(&test_data()).compute_info_hash();
+        let accepting = async {
+            let mut start = [0u8; 20];
+            theirs.read_exact(&mut start).await.unwrap();
+            mse::accept(
+                theirs,
+                &start,
+                &[info_hash],
+                EncryptionMode::PreferPlaintext,
+            )
+            .await
+        };
+        let (ours, theirs) = tokio::join!(
+            mse::connect(ours, &info_hash, EncryptionMode::RequireEncrypted),
+            accepting
+        );
+        let (ours, (theirs, _)) = (ours.unwrap(), theirs.unwrap());
+        assert!(ours.is_encrypted() && theirs.is_encrypted());
+        download_over(ours, theirs).await;
     }
 }
@@ -0,0 +1,309 @@
+use crate::request::bitfield::Bitfield;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+//higher priorities are downloaded first, skipped pieces never
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+//Pieces still to download that no peer is working on. A peer takes only pieces it can get from
+//its remote, and gives back the ones it couldn't finish. Within a priority the pieces the fewest
+//connected peers have go first, or the lowest index when sequential.
+pub struct PiecePicker {
+    pieces: Mutex<Pieces>,
+    returned: Notify,
+}
+
+//the priority, then the rank: how many peers have the piece, always 0 when sequential
+type Key = (Reverse<Priority>, u32, usize);
+
+struct Pieces {
+    //in the order they are picked
+    pending: BTreeSet<Key>,
+    //pieces not at Normal priority, pending or not
+    priorities: HashMap<usize, Priority>,
+    //pieces that failed the hash check are not given again to the peers that sent them, as long
+    //as some other peer may have the piece
+    avoid: HashMap<usize, HashSet<IpAddr>>,
+    //connected peers that have the piece, missing is none
+    availability: HashMap<usize, u32>,
+    //in index order within a priority, suggestions from the peers are ignored
+    sequential: bool,
+}
+
+impl Pieces {
+    fn priority(&self, index: usize) -> Priority {
+        self.priorities.get(&index).copied().unwrap_or_default()
+    }
+
+    fn key(&self, index: usize) -> Key {
+        let rank = match self.sequential {
+            true => 0,
+            false => self.availability.get(&index).copied().unwrap_or(0),
+        };
+        (Reverse(self.priority(index)), rank, index)
+    }
+
+    //the key of a pending piece has to be taken out before what it's made of changes
+    fn rekey(&mut self, index: usize, change: impl FnOnce(&mut Self)) {
+        let was_pending = self.pending.remove(&self.key(index));
+        change(self);
+        if was_pending {
+            let key = self.key(index);
+            self.pending.insert(key);
+        }
+    }
+
+    fn can_take(&self, ip: IpAddr, peer: &Bitfield, index: usize) -> bool {
+        peer.has(index)
+            && match self.avoid.get(&index) {
+                //with no more peers than suspects having it, none of them may be honest
+                Some(suspects) if suspects.contains(&ip) => {
+                    self.availability.get(&index).copied().unwrap_or(0) as usize <= suspects.len()
+                }
+                _ => true,
+            }
+    }
+}
+
+impl PiecePicker {
+    pub fn new(pieces: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            pieces: Mutex::new(Pieces {
+                pending: pieces
+                    .into_iter()
+                    .map(|index| (Reverse(Priority::Normal), 0, index))
+                    .collect(),
+                priorities: HashMap::new(),
+                avoid: HashMap::new(),
+                availability: HashMap::new(),
+                sequential: false,
+            }),
+            returned: Notify::new(),
+        }
+    }
+
+    pub fn sequential(self, sequential: bool) -> Self {
+        self.pieces.lock().unwrap().sequential = sequential;
+        self
+    }
+
+    //the highest priority the peer has, a suggested piece first among equals, then the rarest
+    pub fn pick(&self, ip: IpAddr, peer: &Bitfield, suggested: &[usize]) -> Option<usize> {
+        let mut pieces = self.pieces.lock().unwrap();
+        //a piece no peer has can't be taken from this one, its rank is skipped
+        let rank = match pieces.sequential {
+            true => 0,
+            false => 1,
+        };
+        let first @ (priority, _, _) = [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .find_map(|priority| {
+                pieces
+                    .pending
+                    .range((Reverse(priority), rank, 0)..=(Reverse(priority), u32::MAX, usize::MAX))
+                    .find(|(_, _, index)| pieces.can_take(ip, peer, *index))
+                    .copied()
+            })?;
+        let suggested = if pieces.sequential { &[] } else { suggested };
+        let key = suggested
+            .iter()
+            .map(|index| pieces.key(*index))
+            .filter(|key @ (other, _, index)| {
+                *other == priority
+                    && pieces.pending.contains(key)
+                    && pieces.can_take(ip, peer, *index)
+            })
+            .min()
+            .unwrap_or(first);
+        pieces.pending.remove(&key);
+        Some(key.2)
+    }
+
+    //only among candidates, e.g. the allowed fast pieces while the peer chokes us
+    pub fn pick_among(&self, ip: IpAddr, peer: &Bitfield, candidates: &[usize]) -> Option<usize> {
+        let mut pieces = self.pieces.lock().unwrap();
+        let key = candidates.iter().map(|index| pieces.key(*index)).find(
+            |key @ (Reverse(priority), _, index)| {
+                *priority != Priority::Skip
+                    && pieces.pending.contains(key)
+                    && pieces.can_take(ip, peer, *index)
+            },
+        )?;
+        pieces.pending.remove(&key);
+        Some(key.2)
+    }
+
+    pub fn put_back(&self, index: usize) {
+        let mut pieces = self.pieces.lock().unwrap();
+        let key = pieces.key(index);
+        pieces.pending.insert(key);
+        drop(pieces);
+        self.returned.notify_waiters();
+    }
+
+    //the piece was corrupt, another peer has to send it
+    pub fn put_back_avoiding(&self, index: usize, ips: HashSet<IpAddr>) {
+        self.pieces.lock().unwrap().avoid.insert(index, ips);
+        self.put_back(index);
+    }
+
+    //the piece passed the hash check, its suspects are not needed anymore
+    pub fn forget_suspects(&self, index: usize) {
+        self.pieces.lock().unwrap().avoid.remove(&index);
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.pieces.lock().unwrap().priority(index)
+    }
+
+    //takes effect at the next pick, a piece already being downloaded is finished
+    pub fn set_priority(&self, index: usize, priority: Priority) {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.rekey(index, |pieces| {
+            if priority == Priority::Normal {
+                pieces.priorities.remove(&index);
+            } else {
+                pieces.priorities.insert(index, priority);
+            }
+        });
+        drop(pieces);
+        //a peer with nothing to do may have work now
+        self.returned.notify_waiters();
+    }
+
+    //a peer announced these pieces
+    pub fn add_availability(&self, indexes: impl IntoIterator<Item = usize>) {
+        let mut pieces = self.pieces.lock().unwrap();
+        for index in indexes {
+            pieces.rekey(index, |pieces| {
+                *pieces.availability.entry(index).or_insert(0) += 1;
+            });
+        }
+    }
+
+    //a peer that announced these pieces left, or won't send them
+    pub fn remove_availability(&self, indexes: impl IntoIterator<Item = usize>) {
+        let mut pieces = self.pieces.lock().unwrap();
+        for index in indexes {
+            pieces.rekey(index, |pieces| {
+                if let Some(count) = pieces.availability.get_mut(&index) {
+                    *count -= 1;
+                    if *count == 0 {
+                        pieces.availability.remove(&index);
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.pieces.lock().unwrap().pending.len()
+    }
+
+    //resolves when a piece is given back or a priority changes
+    pub async fn wait(&self) {
+        self.returned.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_only_pieces_the_peer_has() {
+        let picker = PiecePicker::new(0..4);
+        let mut peer = Bitfield::new(4);
+        peer.set(2);
+        peer.set(3);
+        picker.add_availability(peer.pieces());
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        assert_eq!(picker.pick(ip, &peer, &[3]), Some(3));
+        assert_eq!(picker.pick(ip, &peer, &[]), Some(2));
+        assert_eq!(picker.pick(ip, &peer, &[]), None);
+        picker.put_back(3);
+        assert_eq!(picker.pick_among(ip, &peer, &[1, 3]), Some(3));
+        assert_eq!(picker.remaining(), 2);
+
+        //another peer has the piece too
+        picker.add_availability(peer.pieces());
+        picker.put_back_avoiding(3, HashSet::from([ip]));
+        assert_eq!(picker.pick(ip, &peer, &[]), None);
+        assert_eq!(picker.pick([10, 0, 0, 2].into(), &peer, &[]), Some(3));
+    }
+
+    #[test]
+    fn suspects_get_the_piece_when_nobody_else_has_it() {
+        let picker = PiecePicker::new(0..2);
+        let peer = Bitfield::full(2);
+        let (first, second): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        picker.add_availability(peer.pieces());
+        picker.add_availability(peer.pieces());
+        assert_eq!(picker.pick(first, &peer, &[]), Some(0));
+        picker.put_back_avoiding(0, HashSet::from([first]));
+        assert_eq!(picker.pick(first, &peer, &[]), Some(1));
+        picker.put_back(1);
+
+        //the honest peer leaves, the suspect is the only one left with the piece
+        picker.remove_availability(peer.pieces());
+        assert_eq!(picker.pick(first, &peer, &[]), Some(0));
+        picker.put_back_avoiding(0, HashSet::from([first, second]));
+        picker.add_availability(peer.pieces());
+        assert_eq!(picker.pick(second, &peer, &[]), Some(0));
+
+        //a good copy clears the suspects
+        picker.put_back_avoiding(0, HashSet::from([first]));
+        picker.forget_suspects(0);
+        assert_eq!(picker.pick(first, &peer, &[]), Some(0));
+    }
+
+    #[test]
+    fn picks_higher_priorities_first_and_never_skipped_pieces() {
+        let picker = PiecePicker::new(0..4);
+        let mut peer = Bitfield::new(4);
+        (0..4).for_each(|index| peer.set(index));
+        picker.add_availability(peer.pieces());
+        let ip: IpAddr = [10, 0, 0, 1].into();
+        picker.set_priority(0, Priority::Skip);
+        picker.set_priority(1, Priority::Low);
+        picker.set_priority(3, Priority::High);
+        assert_eq!(picker.pick(ip, &peer, &[2]), Some(3));
+        assert_eq!(picker.pick(ip, &peer, &[]), Some(2));
+        assert_eq!(picker.pick(ip, &peer, &[]), Some(1));
+        assert_eq!(picker.pick(ip, &peer, &[]), None);
+        picker.set_priority(0, Priority::Normal);
+        assert_eq!(picker.pick_among(ip, &peer, &[0]), Some(0));
+    }
+
+    #[test]
+    fn picks_the_rarest_piece_unless_sequential() {
+        let full = Bitfield::full(3);
+        let mut rare = Bitfield::new(3);
+        rare.set(2);
+        let ip: IpAddr = [10, 0, 0, 1].into();
+
+        let picker = PiecePicker::new(0..3);
+        picker.add_availability(full.pieces());
+        picker.add_availability(full.pieces());
+        picker.remove_availability([1]);
+        assert_eq!(picker.pick(ip, &full, &[]), Some(1));
+        picker.add_availability(rare.pieces());
+        assert_eq!(picker.pick(ip, &full, &[]), Some(0));
+
+        let picker = PiecePicker::new(0..3).sequential(true);
+        picker.add_availability(full.pieces());
+        picker.add_availability(rare.pieces());
+        assert_eq!(picker.pick(ip, &full, &[]), Some(0));
+        assert_eq!(picker.pick(ip, &full, &[]), Some(1));
+    }
+}
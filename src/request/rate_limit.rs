@@ -0,0 +1,221 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+//the bucket holds at most this much traffic, so an idle connection can't send a big burst
+const BURST: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct BucketState {
+    //bytes per second, None is unlimited
+    rate: Option<u64>,
+    //negative when traffic went through on credit
+    tokens: f64,
+    last: Instant,
+}
+
+//Token bucket in bytes. Traffic is charged after it happened, the bucket may go in debt and the
+//next read or write waits until it is paid back.
+#[derive(Debug)]
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.state.lock().unwrap().rate
+    }
+
+    //takes effect for the next read or write
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.rate = rate;
+        state.tokens = 0.0;
+        state.last = Instant::now();
+    }
+
+    //charges bytes and returns how long to wait before the next transfer
+    pub fn consume(&self, bytes: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let Some(rate) = state.rate.filter(|rate| *rate > 0) else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(rate * BURST.as_secs_f64());
+        state.last = now.max(state.last);
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+//upload and download buckets of one scope: the client, a torrent or a peer
+#[derive(Debug, Clone)]
+pub struct BandwidthLimits {
+    pub upload: Arc<TokenBucket>,
+    pub download: Arc<TokenBucket>,
+}
+
+impl BandwidthLimits {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self {
+            upload: Arc::new(TokenBucket::new(upload)),
+            download: Arc::new(TokenBucket::new(download)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn set(&self, upload: Option<u64>, download: Option<u64>) {
+        self.upload.set_rate(upload);
+        self.download.set_rate(download);
+    }
+}
+
+impl Default for BandwidthLimits {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+//A socket half whose traffic is charged to every bucket in the chain, the slowest one decides
+//when the next read or write can happen.
+pub struct Throttled<S> {
+    inner: S,
+    buckets: Vec<Arc<TokenBucket>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            buckets,
+            delay: None,
+        }
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn charge(&mut self, bytes: usize) {
+        let now = Instant::now();
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.consume(bytes, now))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_delay(cx));
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+        self.charge(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_delay(cx));
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.charge(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn about(wait: Duration, millis: u64) -> bool {
+        wait.abs_diff(Duration::from_millis(millis)) < Duration::from_millis(5)
+    }
+
+    #[test]
+    fn bucket_spreads_traffic_over_time() {
+        let bucket = TokenBucket::new(Some(1000));
+        let start = Instant::now();
+        assert!(about(bucket.consume(500, start), 500));
+        //the debt is paid after half a second, then the burst allowance refills
+        assert!(about(
+            bucket.consume(100, start + Duration::from_millis(500)),
+            100
+        ));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.consume(100, later), Duration::ZERO);
+        assert!(bucket.consume(100, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn rate_can_change_at_runtime() {
+        let bucket = TokenBucket::new(None);
+        assert_eq!(bucket.consume(1 << 30, Instant::now()), Duration::ZERO);
+        bucket.set_rate(Some(100));
+        assert!(bucket.consume(100, Instant::now()) > Duration::ZERO);
+        bucket.set_rate(None);
+        assert_eq!(bucket.consume(100, Instant::now()), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn writes_are_charged_to_every_bucket() {
+        let global = Arc::new(TokenBucket::new(None));
+        let peer = Arc::new(TokenBucket::new(Some(1_000_000)));
+        let (client, _server) = tokio::io::duplex(64 * 1024);
+        let mut writer = Throttled::new(client, vec![global, Arc::clone(&peer)]);
+        tokio::io::AsyncWriteExt::write_all(&mut writer, &[0; 50_000])
+            .await
+            .unwrap();
+        assert!(writer.delay.is_some());
+        assert!(peer.consume(0, Instant::now()) > Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::parser::torrent_file::TorrentInfo;
+use crate::request::bitfield::Bitfield;
+use crate::request::client::ClientError;
+use crate::request::storage::Storage;
+use std::io;
+use tokio::task::JoinSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecheckProgress {
+    pub checked: usize,
+    pub total: usize,
+    //pieces that matched their hash so far
+    pub valid: usize,
+}
+
+//Hashes every piece in storage against the torrent, the ones that match are the pieces we have.
+//Storage::check_piece gets the pieces one after the other and they are hashed in parallel on the
+//blocking pool; progress is called once per piece, in no particular order.
+pub async fn recheck(
+    storage: &mut dyn Storage,
+    info: &TorrentInfo,
+    mut progress: impl FnMut(RecheckProgress),
+) -> Result<Bitfield, ClientError> {
+    let hashes = info.get_divided_pieces();
+    //enough pieces in memory to keep every core busy while the next one is read
+    let in_flight = std::thread::available_parallelism().map_or(1, |cores| cores.get()) * 2;
+    let mut bitfield = Bitfield::new(hashes.len());
+    let mut report = RecheckProgress {
+        checked: 0,
+        total: hashes.len(),
+        valid: 0,
+    };
+    let mut hashing = JoinSet::new();
+    let mut on_hashed = |hashed: Result<(usize, io::Result<bool>), _>, bitfield: &mut Bitfield| {
+        let (index, valid) = hashed.map_err(io::Error::other)?;
+        let valid = valid?;
+        report.checked += 1;
+        if valid {
+            bitfield.set(index);
+            report.valid += 1;
+        }
+        progress(report);
+        Ok::<_, ClientError>(())
+    };
+
+    for (index, hash) in hashes.into_iter().enumerate() {
+        if hashing.len() >= in_flight
+            && let Some(hashed) = hashing.join_next().await
+        {
+            on_hashed(hashed, &mut bitfield)?;
+        }
+        let check = match storage
+            .check_piece(index, info.piece_size(index), hash)
+            .await
+        {
+            Ok(check) => check,
+            //data shorter than the torrent, the rest is missing
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                hashing.spawn(async move { (index, Ok(false)) });
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        hashing.spawn(async move { (index, check.await) });
+    }
+    while let Some(hashed) = hashing.join_next().await {
+        on_hashed(hashed, &mut bitfield)?;
+    }
+    Ok(bitfield)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::torrent_file::TorrentFile;
+    use crate::request::storage::MemoryStorage;
+    use sha1::{Digest, Sha1};
+
+    #[tokio::test]
+    async fn recheck_finds_the_pieces_that_match() {
+        let data: Vec<u8> = (0..10u8).collect();
+        let pieces: Vec<u8> = data
+            .chunks(4)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        let mut bencode =
+            b"d4:infod6:lengthi10e4:name7:recheck12:piece lengthi4e6:pieces60:".to_vec();
+        bencode.extend_from_slice(&pieces);
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[5] = 0xFF;
+        let mut storage = MemoryStorage::with_data(corrupted, 4);
+        let mut reports = Vec::new();
+        let bitfield = recheck(&mut storage, &torrent.info, |report| reports.push(report))
+            .await
+            .unwrap();
+        assert_eq!(bitfield.difference(&Bitfield::new(3)), vec![0, 2]);
+        assert_eq!(reports.len(), 3);
+        assert_eq!(
+            reports.last(),
+            Some(&RecheckProgress {
+                checked: 3,
+                total: 3,
+                valid: 2
+            })
+        );
+    }
+}
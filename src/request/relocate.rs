@@ -0,0 +1,259 @@
+use crate::request::resume::resume_path;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+//bytes copied at once between filesystems
+const COPY_CHUNK: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoveProgress {
+    pub moved: u64,
+    pub total: u64,
+}
+
+//Puts the file at from at to: a rename on the same filesystem, a copy otherwise. Returns true if
+//it was copied, from is then still there for the caller to remove once nothing needs it.
+pub async fn move_file(
+    from: &Path,
+    to: &Path,
+    mut progress: impl FnMut(MoveProgress),
+) -> io::Result<bool> {
+    let total = fs::metadata(from).await?.len();
+    match fs::rename(from, to).await {
+        Ok(()) => {
+            progress(MoveProgress {
+                moved: total,
+                total,
+            });
+            Ok(false)
+        }
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_file(from, to, progress).await?;
+            Ok(true)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+//true when a rename can move from into the directory, no copy and so no space needed
+pub async fn same_filesystem(from: &Path, directory: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(fs::metadata(from).await?.dev() == fs::metadata(directory).await?.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (from, directory);
+        Ok(false)
+    }
+}
+
+//Copies through <to>.part, synced and then renamed, so to is either missing or complete.
+pub async fn copy_file(
+    from: &Path,
+    to: &Path,
+    progress: impl FnMut(MoveProgress),
+) -> io::Result<()> {
+    let mut part = to.as_os_str().to_owned();
+    part.push(".part");
+    let copied = copy_to_part(from, Path::new(&part), progress).await;
+    if copied.is_err() {
+        //whatever got copied is of no use, and may fill the disk
+        let _ = fs::remove_file(&part).await;
+    }
+    copied?;
+    fs::rename(&part, to).await
+}
+
+async fn copy_to_part(
+    from: &Path,
+    part: &Path,
+    mut progress: impl FnMut(MoveProgress),
+) -> io::Result<()> {
+    let mut source = File::open(from).await?;
+    let total = source.metadata().await?.len();
+    let mut target = File::create(part).await?;
+    let mut buffer = vec![0u8; COPY_CHUNK];
+    let mut moved = 0;
+    loop {
+        let read = source.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        target.write_all(&buffer[..read]).await?;
+        moved += read as u64;
+        progress(MoveProgress { moved, total });
+    }
+    target.sync_all().await
+}
+
+//Written next to the data at both ends before a move, removed once the move is over. A move cut
+//short by a crash is finished or undone by the next start, from either directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MoveJournal {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl MoveJournal {
+    pub async fn write(&self) -> io::Result<()> {
+        let bytes = serde_bencode::to_bytes(self).map_err(io::Error::other)?;
+        for data in [&self.from, &self.to] {
+            let mut file = File::create(journal_path(data)).await?;
+            file.write_all(&bytes).await?;
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self) -> io::Result<()> {
+        for data in [&self.from, &self.to] {
+            remove_if_exists(&journal_path(data)).await?;
+        }
+        Ok(())
+    }
+
+    //Where the data at data is after a move that didn't finish, None when there was no move.
+    //A complete copy at the destination wins, the data stays at the source otherwise.
+    pub async fn recover(data: &Path) -> io::Result<Option<PathBuf>> {
+        let journal = match fs::read(journal_path(data)).await {
+            Ok(bytes) => serde_bencode::from_bytes::<MoveJournal>(&bytes).ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        //cut short while it was written, nothing was moved yet
+        let Some(journal) = journal else {
+            fs::remove_file(journal_path(data)).await?;
+            return Ok(None);
+        };
+        //a copy goes through <to>.part, to is complete whenever it is there
+        let moved = fs::try_exists(&journal.to).await?;
+        let location = match moved {
+            true => {
+                if fs::try_exists(&journal.from).await? {
+                    fs::remove_file(&journal.from).await?;
+                }
+                //the resume file follows the data, a copy makes it stale and the data is checked
+                let (old, new) = (resume_path(&journal.from), resume_path(&journal.to));
+                match fs::try_exists(&new).await? {
+                    true => remove_if_exists(&old).await?,
+                    false if fs::try_exists(&old).await? => {
+                        move_file(&old, &new, |_| ()).await?;
+                        remove_if_exists(&old).await?;
+                    }
+                    false => (),
+                }
+                journal.to.clone()
+            }
+            false => {
+                let mut part = journal.to.as_os_str().to_owned();
+                part.push(".part");
+                remove_if_exists(Path::new(&part)).await?;
+                journal.from.clone()
+            }
+        };
+        journal.remove().await?;
+        Ok(Some(location))
+    }
+}
+
+//<data>.move
+fn journal_path(data: &Path) -> PathBuf {
+    let mut path = data.as_os_str().to_owned();
+    path.push(".move");
+    PathBuf::from(path)
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn copy_leaves_a_complete_file_and_the_source() {
+        let directory = std::env::temp_dir().join(format!("relocate-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let from = directory.join("from");
+        let to = directory.join("to");
+        let data: Vec<u8> = (0..COPY_CHUNK + 10).map(|i| i as u8).collect();
+        std::fs::write(&from, &data).unwrap();
+
+        let mut reports = Vec::new();
+        copy_file(&from, &to, |report| reports.push(report))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), data);
+        assert!(from.exists());
+        assert!(!directory.join("to.part").exists());
+        assert_eq!(
+            reports.last(),
+            Some(&MoveProgress {
+                moved: data.len() as u64,
+                total: data.len() as u64
+            })
+        );
+
+        //same filesystem, a rename
+        let moved = directory.join("moved");
+        assert!(!move_file(&to, &moved, |_| ()).await.unwrap());
+        assert!(!to.exists() && moved.exists());
+        assert!(same_filesystem(&moved, &directory).await.unwrap());
+
+        //a directory opens but can't be read, the copy fails halfway
+        let failed = directory.join("failed");
+        assert!(copy_file(&directory, &failed, |_| ()).await.is_err());
+        assert!(!failed.exists());
+        assert!(!directory.join("failed.part").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn interrupted_moves_are_finished_or_undone() {
+        let directory = std::env::temp_dir().join(format!("journal-{}", std::process::id()));
+        let (old, new) = (directory.join("old"), directory.join("new"));
+        std::fs::create_dir_all(&old).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        let journal = MoveJournal {
+            from: old.join("data"),
+            to: new.join("data"),
+        };
+        assert_eq!(MoveJournal::recover(&journal.from).await.unwrap(), None);
+
+        //the copy was complete, the old data and its resume file were still there
+        std::fs::write(&journal.from, b"data").unwrap();
+        std::fs::write(resume_path(&journal.from), b"resume").unwrap();
+        std::fs::write(&journal.to, b"data").unwrap();
+        journal.write().await.unwrap();
+        let location = MoveJournal::recover(&journal.from).await.unwrap();
+        assert_eq!(location, Some(journal.to.clone()));
+        assert!(!journal.from.exists() && journal.to.exists());
+        assert_eq!(std::fs::read(resume_path(&journal.to)).unwrap(), b"resume");
+        assert!(!resume_path(&journal.from).exists());
+        assert!(!journal_path(&journal.from).exists() && !journal_path(&journal.to).exists());
+
+        //the copy didn't finish, found from the new directory the data stays where it was
+        let back = MoveJournal {
+            from: journal.to.clone(),
+            to: journal.from.clone(),
+        };
+        std::fs::write(new.join("data.move"), b"d4:from").unwrap();
+        assert_eq!(MoveJournal::recover(&back.from).await.unwrap(), None);
+        std::fs::write(old.join("data.part"), b"da").unwrap();
+        back.write().await.unwrap();
+        let location = MoveJournal::recover(&back.from).await.unwrap();
+        assert_eq!(location, Some(back.from.clone()));
+        assert!(!old.join("data.part").exists());
+        assert!(!journal_path(&back.from).exists() && !journal_path(&back.to).exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+use crate::request::blame::DownloadedPiece;
+use crate::request::torrent_message::TorrentMessage;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+pub const BLOCK_LENGTH: u32 = 16384;
+//used until the peer tells us its reqq in the extended handshake
+pub const DEFAULT_PEER_REQQ: usize = 64;
+const MIN_QUEUE_DEPTH: usize = 4;
+const INITIAL_QUEUE_DEPTH: usize = 16;
+//the bandwidth delay product is doubled so the pipe stays full while the answers travel back
+const BDP_HEADROOM: f64 = 2.0;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+//upper bound on how long we sleep waiting for a message when nothing is about to expire
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn to_message(self) -> TorrentMessage {
+        TorrentMessage::Request {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+//what became of a block the peer sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    //asked for, the piece still misses other blocks
+    Accepted,
+    //the last block of the piece, here is the whole piece
+    Completed(DownloadedPiece),
+    //not asked for or already there, it counts for nothing
+    Dropped,
+}
+
+//a block of a piece not complete yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedBlock {
+    pub data: Vec<u8>,
+    //None for a block read back from disk after a restart
+    pub from: Option<IpAddr>,
+}
+
+struct PieceInProgress {
+    blocks: Vec<Option<ReceivedBlock>>,
+    missing: usize,
+}
+
+//Keeps the block requests of one peer. Pieces are split in blocks that are queued one after the
+//other, so when a piece is almost done the requests for the next one are already on the wire.
+pub struct RequestQueue {
+    fixed_depth: Option<usize>,
+    peer_reqq: usize,
+    block_timeout: Duration,
+    pending: VecDeque<BlockRequest>,
+    outstanding: HashMap<BlockRequest, Instant>,
+    pieces: HashMap<u32, PieceInProgress>,
+    min_rtt: Option<Duration>,
+    srtt: Option<Duration>,
+    //bytes per second, smoothed over RATE_WINDOW samples
+    rate: f64,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl RequestQueue {
+    pub fn new(fixed_depth: Option<usize>, block_timeout: Duration) -> Self {
+        Self {
+            fixed_depth,
+            peer_reqq: DEFAULT_PEER_REQQ,
+            block_timeout,
+            pending: VecDeque::new(),
+            outstanding: HashMap::new(),
+            pieces: HashMap::new(),
+            min_rtt: None,
+            srtt: None,
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    pub fn set_peer_reqq(&mut self, reqq: usize) {
+        self.peer_reqq = reqq.max(1);
+    }
+
+    //how many requests we want on the wire
+    pub fn depth(&self) -> usize {
+        if let Some(depth) = self.fixed_depth {
+            return depth.min(self.peer_reqq).max(1);
+        }
+        let depth = match self.min_rtt {
+            Some(rtt) if self.rate > 0.0 => {
+                let bdp = self.rate * rtt.as_secs_f64() * BDP_HEADROOM;
+                (bdp / BLOCK_LENGTH as f64).ceil() as usize
+            }
+            _ => INITIAL_QUEUE_DEPTH,
+        };
+        depth.max(MIN_QUEUE_DEPTH).min(self.peer_reqq)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    //true when the blocks already queued are not enough to keep the pipe full
+    pub fn needs_pieces(&self) -> bool {
+        self.pending.len() + self.outstanding.len() < self.depth()
+    }
+
+    pub fn add_piece(&mut self, index: usize, piece_size: usize) {
+        self.add_partial_piece(index, piece_size, Vec::new());
+    }
+
+    //only the blocks missing from received are asked for, received has one slot per block or
+    //none at all
+    pub fn add_partial_piece(
+        &mut self,
+        index: usize,
+        piece_size: usize,
+        mut received: Vec<Option<ReceivedBlock>>,
+    ) {
+        let index = index as u32;
+        let number_of_blocks = piece_size.div_ceil(BLOCK_LENGTH as usize);
+        if received.len() != number_of_blocks {
+            received = vec![None; number_of_blocks];
+        }
+        for (block, _) in received.iter().enumerate().filter(|(_, b)| b.is_none()) {
+            let begin = block * BLOCK_LENGTH as usize;
+            self.pending.push_back(BlockRequest {
+                index,
+                begin: begin as u32,
+                length: (piece_size - begin).min(BLOCK_LENGTH as usize) as u32,
+            });
+        }
+        let missing = received.iter().filter(|block| block.is_none()).count();
+        self.pieces.insert(
+            index,
+            PieceInProgress {
+                blocks: received,
+                missing,
+            },
+        );
+    }
+
+    //blocks whose answer is late are asked again, then new blocks fill the queue up to depth
+    pub fn next_requests(&mut self, now: Instant) -> Vec<BlockRequest> {
+        self.next_requests_among(now, |_| true)
+    }
+
+    //like next_requests, but only blocks of the pieces accepted by allowed are sent, e.g. the
+    //allowed fast pieces while the peer chokes us
+    pub fn next_requests_among(
+        &mut self,
+        now: Instant,
+        allowed: impl Fn(u32) -> bool,
+    ) -> Vec<BlockRequest> {
+        let timeout = self.request_timeout();
+        let mut late: Vec<BlockRequest> = self
+            .outstanding
+            .iter()
+            .filter(|(_, sent_at)| now.duration_since(**sent_at) >= timeout)
+            .map(|(request, _)| *request)
+            .collect();
+        late.sort_by_key(|r| (r.index, r.begin));
+        let (mut requests, waiting): (Vec<_>, Vec<_>) =
+            late.into_iter().partition(|request| allowed(request.index));
+        for request in &requests {
+            self.outstanding.insert(*request, now);
+        }
+        //late blocks we can't ask again now wait in front of the queue, not in the deadline
+        for request in waiting.into_iter().rev() {
+            self.outstanding.remove(&request);
+            self.pending.push_front(request);
+        }
+
+        let depth = self.depth();
+        let mut position = 0;
+        while self.outstanding.len() < depth && position < self.pending.len() {
+            if !allowed(self.pending[position].index) {
+                position += 1;
+                continue;
+            }
+            let request = self.pending.remove(position).unwrap();
+            self.outstanding.insert(request, now);
+            requests.push(request);
+        }
+        requests
+    }
+
+    //a block we are not waiting for, with the same index, begin and length, is dropped so it
+    //can't take the place of the real one
+    pub fn on_block(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+        from: IpAddr,
+        now: Instant,
+    ) -> Received {
+        let request = BlockRequest {
+            index,
+            begin,
+            length: block.len() as u32,
+        };
+        let Some(sent_at) = self.outstanding.remove(&request) else {
+            return Received::Dropped;
+        };
+        let block_index = (begin / BLOCK_LENGTH) as usize;
+        let missing = self
+            .pieces
+            .get(&index)
+            .and_then(|piece| piece.blocks.get(block_index))
+            .is_some_and(Option::is_none);
+        if !missing {
+            return Received::Dropped;
+        }
+        self.update_rtt(now.duration_since(sent_at));
+        self.update_rate(block.len(), now);
+        let piece = self.pieces.get_mut(&index).unwrap();
+        piece.blocks[block_index] = Some(ReceivedBlock {
+            data: block,
+            from: Some(from),
+        });
+        piece.missing -= 1;
+        if piece.missing > 0 {
+            return Received::Accepted;
+        }
+
+        let piece = self.pieces.remove(&index).unwrap();
+        self.pending.retain(|r| r.index != index);
+        self.outstanding.retain(|r, _| r.index != index);
+        let (blocks, contributors): (Vec<_>, Vec<_>) = piece
+            .blocks
+            .into_iter()
+            .flatten()
+            .map(|block| (block.data, block.from))
+            .unzip();
+        Received::Completed(DownloadedPiece {
+            index: index as usize,
+            data: blocks.concat(),
+            contributors,
+        })
+    }
+
+    //a choking peer discards the requests it has received, they go back in front of the queue
+    pub fn on_choke(&mut self) {
+        let mut outstanding: Vec<BlockRequest> = self.outstanding.drain().map(|(r, _)| r).collect();
+        outstanding.sort_by_key(|r| (r.index, r.begin));
+        for request in outstanding.into_iter().rev() {
+            self.pending.push_front(request);
+        }
+    }
+
+    //the peer told us it won't answer, the block waits in front of the queue for an unchoke
+    pub fn on_reject(&mut self, request: BlockRequest) {
+        if self.outstanding.remove(&request).is_some() {
+            self.pending.push_front(request);
+        }
+    }
+
+    //drops a piece the peer refuses to send, with the blocks it did send; None if we were not
+    //downloading it
+    pub fn abandon_piece(&mut self, index: u32) -> Option<Vec<Option<ReceivedBlock>>> {
+        self.pending.retain(|r| r.index != index);
+        self.outstanding.retain(|r, _| r.index != index);
+        self.pieces.remove(&index).map(|piece| piece.blocks)
+    }
+
+    //how long we can wait for a message before a block has to be requested again
+    pub fn next_deadline(&self, now: Instant) -> Duration {
+        let timeout = self.request_timeout();
+        self.outstanding
+            .values()
+            .map(|sent_at| (*sent_at + timeout).saturating_duration_since(now))
+            .min()
+            .unwrap_or(MAX_WAIT)
+            .min(MAX_WAIT)
+    }
+
+    //pieces not completed yet with the blocks received, so that another peer can finish them
+    pub fn take_unfinished(&mut self) -> Vec<(usize, Vec<Option<ReceivedBlock>>)> {
+        self.pending.clear();
+        self.outstanding.clear();
+        self.pieces
+            .drain()
+            .map(|(index, piece)| (index as usize, piece.blocks))
+            .collect()
+    }
+
+    fn request_timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => self.block_timeout.max(srtt * 2),
+            None => self.block_timeout,
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        self.min_rtt = Some(self.min_rtt.map_or(sample, |rtt| rtt.min(sample)));
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    fn update_rate(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            self.rate * 0.8 + sample * 0.2
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn requests_span_piece_boundaries() {
+        let mut queue = RequestQueue::new(Some(6), Duration::from_secs(10));
+        queue.add_piece(0, 2 * BLOCK_LENGTH as usize);
+        assert!(queue.needs_pieces());
+        queue.add_piece(1, 2 * BLOCK_LENGTH as usize + 10);
+        assert!(queue.needs_pieces());
+        queue.add_piece(2, BLOCK_LENGTH as usize);
+
+        let requests = queue.next_requests(Instant::now());
+        assert_eq!(requests.len(), 6);
+        assert_eq!(
+            requests[4],
+            BlockRequest {
+                index: 1,
+                begin: 2 * BLOCK_LENGTH,
+                length: 10
+            }
+        );
+        assert_eq!(requests[5].index, 2);
+    }
+
+    #[test]
+    fn blocks_are_requested_again_only_after_timeout() {
+        let mut queue = RequestQueue::new(Some(2), Duration::from_secs(10));
+        queue.add_piece(0, 2 * BLOCK_LENGTH as usize);
+        let start = Instant::now();
+        assert_eq!(queue.next_requests(start).len(), 2);
+        assert!(
+            queue
+                .next_requests(start + Duration::from_secs(5))
+                .is_empty()
+        );
+        assert_eq!(
+            queue.next_requests(start + Duration::from_secs(11)).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn piece_is_returned_when_complete() {
+        let mut queue = RequestQueue::new(None, Duration::from_secs(10));
+        queue.add_piece(3, BLOCK_LENGTH as usize + 2);
+        let now = Instant::now();
+        queue.next_requests(now);
+        assert_eq!(
+            queue.on_block(3, BLOCK_LENGTH, vec![1, 2], PEER, now),
+            Received::Accepted
+        );
+        let Received::Completed(piece) =
+            queue.on_block(3, 0, vec![0; BLOCK_LENGTH as usize], PEER, now)
+        else {
+            panic!("the last block didn't complete the piece");
+        };
+        assert_eq!(piece.index, 3);
+        assert_eq!(piece.data.len(), BLOCK_LENGTH as usize + 2);
+        assert_eq!(&piece.data[BLOCK_LENGTH as usize..], &[1, 2]);
+        assert_eq!(piece.contributors, vec![Some(PEER), Some(PEER)]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn misaligned_and_unrequested_blocks_are_dropped() {
+        let mut queue = RequestQueue::new(Some(1), Duration::from_secs(10));
+        queue.add_piece(0, 2 * BLOCK_LENGTH as usize);
+        let now = Instant::now();
+        assert_eq!(queue.next_requests(now).len(), 1);
+        //the second block was not requested yet, one byte off the first isn't a block at all
+        let second = vec![2; BLOCK_LENGTH as usize];
+        assert_eq!(
+            queue.on_block(0, BLOCK_LENGTH, second.clone(), PEER, now),
+            Received::Dropped
+        );
+        assert_eq!(
+            queue.on_block(0, 1, vec![9; BLOCK_LENGTH as usize], PEER, now),
+            Received::Dropped
+        );
+        assert_eq!(
+            queue.on_block(0, 0, vec![9; 10], PEER, now),
+            Received::Dropped
+        );
+
+        assert_eq!(
+            queue.on_block(0, 0, vec![1; BLOCK_LENGTH as usize], PEER, now),
+            Received::Accepted
+        );
+        assert_eq!(queue.next_requests(now).len(), 1);
+        let Received::Completed(piece) = queue.on_block(0, BLOCK_LENGTH, second, PEER, now) else {
+            panic!("the last block didn't complete the piece");
+        };
+        assert_eq!(piece.data[0], 1);
+        assert_eq!(piece.data[BLOCK_LENGTH as usize], 2);
+    }
+
+    #[test]
+    fn unfinished_pieces_keep_their_blocks() {
+        let mut queue = RequestQueue::new(Some(4), Duration::from_secs(10));
+        queue.add_piece(0, 3 * BLOCK_LENGTH as usize);
+        let now = Instant::now();
+        queue.next_requests(now);
+        queue.on_block(0, BLOCK_LENGTH, vec![1; BLOCK_LENGTH as usize], PEER, now);
+        let mut unfinished = queue.take_unfinished();
+        let (index, blocks) = unfinished.pop().unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(blocks.iter().flatten().count(), 1);
+
+        //another peer asks only for the blocks still missing
+        let mut other = RequestQueue::new(Some(4), Duration::from_secs(10));
+        other.add_partial_piece(0, 3 * BLOCK_LENGTH as usize, blocks);
+        let requests = other.next_requests(now);
+        assert_eq!(
+            requests.iter().map(|r| r.begin).collect::<Vec<_>>(),
+            vec![0, 2 * BLOCK_LENGTH]
+        );
+        other.on_block(0, 0, vec![0; BLOCK_LENGTH as usize], PEER, now);
+        let Received::Completed(piece) = other.on_block(
+            0,
+            2 * BLOCK_LENGTH,
+            vec![2; BLOCK_LENGTH as usize],
+            PEER,
+            now,
+        ) else {
+            panic!("the last block didn't complete the piece");
+        };
+        assert_eq!(piece.data[BLOCK_LENGTH as usize], 1);
+        assert_eq!(piece.contributors, vec![Some(PEER); 3]);
+    }
+
+    #[test]
+    fn choke_puts_outstanding_back_in_front() {
+        let mut queue = RequestQueue::new(Some(1), Duration::from_secs(10));
+        queue.add_piece(0, 2 * BLOCK_LENGTH as usize);
+        let first = queue.next_requests(Instant::now());
+        queue.on_choke();
+        assert_eq!(queue.next_requests(Instant::now()), first);
+    }
+
+    #[test]
+    fn only_allowed_pieces_are_requested_and_rejects_requeue() {
+        let mut queue = RequestQueue::new(Some(4), Duration::from_secs(10));
+        queue.add_piece(0, BLOCK_LENGTH as usize);
+        queue.add_piece(1, 2 * BLOCK_LENGTH as usize);
+        let now = Instant::now();
+        let requests = queue.next_requests_among(now, |index| index == 1);
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.index == 1));
+
+        queue.on_reject(requests[1]);
+        let requests = queue.next_requests(now);
+        assert_eq!(requests[0].index, 1);
+        assert_eq!(requests[1].index, 0);
+        assert!(queue.abandon_piece(1).is_some());
+        assert!(queue.abandon_piece(1).is_none());
+    }
+
+    #[test]
+    fn late_blocks_that_cant_be_asked_again_leave_the_deadline() {
+        let mut queue = RequestQueue::new(Some(2), Duration::from_secs(10));
+        queue.add_piece(0, 2 * BLOCK_LENGTH as usize);
+        let start = Instant::now();
+        let sent = queue.next_requests(start);
+        assert_eq!(sent.len(), 2);
+
+        //choked with the fast extension, the requests stay outstanding until they are late
+        let late = start + Duration::from_secs(11);
+        assert_eq!(queue.next_deadline(late), Duration::ZERO);
+        assert!(queue.next_requests_among(late, |_| false).is_empty());
+        assert_eq!(queue.next_deadline(late), MAX_WAIT);
+        assert_eq!(queue.next_requests(late), sent);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::request::bitfield::Bitfield;
+use crate::request::request_queue::{BLOCK_LENGTH, ReceivedBlock};
+use crate::request::storage::Storage;
+use crate::request::torrent_state::TorrentState;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+//blocks of a piece written to storage before the piece was complete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub index: usize,
+    //bit 7 of the first byte is the first block
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+}
+
+impl PartialPiece {
+    //the blocks back from storage, one slot per block of the piece
+    pub async fn read(
+        &self,
+        storage: &mut dyn Storage,
+        piece_size: usize,
+    ) -> io::Result<Vec<Option<ReceivedBlock>>> {
+        let number_of_blocks = piece_size.div_ceil(BLOCK_LENGTH as usize);
+        let stored = Bitfield::from_bytes(&self.blocks, number_of_blocks);
+        let mut blocks = Vec::with_capacity(number_of_blocks);
+        for block in 0..number_of_blocks {
+            if !stored.has(block) {
+                blocks.push(None);
+                continue;
+            }
+            let begin = block * BLOCK_LENGTH as usize;
+            let length = (piece_size - begin).min(BLOCK_LENGTH as usize);
+            let data = storage.read_block(self.index, begin, length).await?;
+            blocks.push(Some(ReceivedBlock { data, from: None }));
+        }
+        Ok(blocks)
+    }
+}
+
+//a data file as it was when the resume data was written, any change means it was touched
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub path: String,
+    pub size: u64,
+    //seconds since the epoch
+    pub mtime: u64,
+}
+
+//Everything needed to pick a download up where it stopped without checking every piece.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash", with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    #[serde(rename = "number of pieces")]
+    pub number_of_pieces: usize,
+    //the verified pieces, a bitfield like on the wire
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    //pieces missing from pieces with some blocks on disk, read back and checked at start
+    #[serde(default)]
+    pub partial: Vec<PartialPiece>,
+    pub files: Vec<FileStamp>,
+    //peers worth dialing again, as ip:port
+    pub peers: Vec<String>,
+}
+
+impl ResumeData {
+    pub fn peer_addresses(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter_map(|peer| peer.parse().ok())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum Resume {
+    //a new download, there is nothing on disk
+    Missing,
+    Valid(ResumeData),
+    //the data on disk can't be trusted without a recheck
+    Stale(String),
+}
+
+#[derive(Debug)]
+struct Location {
+    path: PathBuf,
+    //data files whose size and mtime are recorded, none for storage we don't manage
+    files: Vec<PathBuf>,
+}
+
+impl Location {
+    fn new(data: &Path, files: Vec<PathBuf>) -> Self {
+        Self {
+            path: resume_path(data),
+            files,
+        }
+    }
+}
+
+//<data>.resume
+pub fn resume_path(data: &Path) -> PathBuf {
+    let mut path = data.as_os_str().to_owned();
+    path.push(".resume");
+    PathBuf::from(path)
+}
+
+//<data>.resume next to the data, replaced atomically at every save. Clones share the location,
+//so they all follow a move.
+#[derive(Debug, Clone)]
+pub struct ResumeFile {
+    //held for a whole save, a move waits for it
+    location: Arc<Mutex<Location>>,
+}
+
+impl ResumeFile {
+    pub fn new(data: impl AsRef<Path>, files: Vec<PathBuf>) -> Self {
+        Self {
+            location: Arc::new(Mutex::new(Location::new(data.as_ref(), files))),
+        }
+    }
+
+    //call before opening the storage, which may touch the files
+    pub async fn load(&self, info_hash: [u8; 20], number_of_pieces: usize) -> Resume {
+        let location = self.location.lock().await;
+        let bytes = match fs::read(&location.path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                //data from an older client or copied in by hand
+                return match Self::stamps(&location.files).await {
+                    Ok(stamps) if stamps.iter().any(|stamp| stamp.size > 0) => {
+                        Resume::Stale("data without resume file".to_string())
+                    }
+                    _ => Resume::Missing,
+                };
+            }
+            Err(e) => return Resume::Stale(e.to_string()),
+        };
+        let data: ResumeData = match serde_bencode::from_bytes(&bytes) {
+            Ok(data) => data,
+            Err(e) => return Resume::Stale(format!("corrupt resume file: {}", e)),
+        };
+        if data.info_hash != info_hash {
+            return Resume::Stale("resume file of another torrent".to_string());
+        }
+        if data.number_of_pieces != number_of_pieces
+            || data.pieces.len() != number_of_pieces.div_ceil(8)
+        {
+            return Resume::Stale("resume file with a different number of pieces".to_string());
+        }
+        match Self::stamps(&location.files).await {
+            Ok(stamps) if stamps == data.files => Resume::Valid(data),
+            Ok(_) => Resume::Stale("files changed since the resume file was written".to_string()),
+            Err(e) => Resume::Stale(e.to_string()),
+        }
+    }
+
+    pub async fn save(&self, state: &TorrentState) -> io::Result<()> {
+        let location = self.location.lock().await;
+        Self::write(&location, state).await
+    }
+
+    //resume data without a running download, e.g. after a recheck
+    pub async fn save_pieces(&self, info_hash: [u8; 20], pieces: &Bitfield) -> io::Result<()> {
+        let location = self.location.lock().await;
+        Self::write_pieces(&location, info_hash, pieces, &[], &[]).await
+    }
+
+    //Saves next to the data moved to data and only then removes the old resume file, a crash in
+    //between leaves two valid ones.
+    pub async fn move_to(
+        &self,
+        data: impl AsRef<Path>,
+        files: Vec<PathBuf>,
+        state: &TorrentState,
+    ) -> io::Result<()> {
+        let mut location = self.location.lock().await;
+        let moved = Location::new(data.as_ref(), files);
+        Self::write(&moved, state).await?;
+        let previous = std::mem::replace(&mut *location, moved);
+        match fs::remove_file(&previous.path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn write(location: &Location, state: &TorrentState) -> io::Result<()> {
+        let peers = state.pool().addresses();
+        let partial: Vec<PartialPiece> = state
+            .stored_blocks()
+            .into_iter()
+            .map(|(index, blocks)| PartialPiece {
+                index,
+                blocks: blocks.as_bytes().to_vec(),
+            })
+            .collect();
+        let pieces = state.bitfield();
+        Self::write_pieces(location, state.info_hash(), &pieces, &partial, &peers).await
+    }
+
+    async fn write_pieces(
+        location: &Location,
+        info_hash: [u8; 20],
+        pieces: &Bitfield,
+        partial: &[PartialPiece],
+        peers: &[SocketAddr],
+    ) -> io::Result<()> {
+        let data = ResumeData {
+            info_hash: info_hash.to_vec(),
+            number_of_pieces: pieces.len(),
+            pieces: pieces.as_bytes().to_vec(),
+            partial: partial.to_vec(),
+            files: Self::stamps(&location.files).await?,
+            peers: peers.iter().map(|address| address.to_string()).collect(),
+        };
+        let bytes = serde_bencode::to_bytes(&data).map_err(io::Error::other)?;
+
+        //a crash leaves either the old file or the new one, never half of it
+        let mut temporary = location.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = fs::File::create(&temporary).await?;
+        file.write_all(&bytes).await?;
+        file.sync_all().await?;
+        drop(file);
+        fs::rename(&temporary, &location.path).await?;
+        debug!("Saved resume data to {}", location.path.display());
+        Ok(())
+    }
+
+    //a file that doesn't exist counts as empty
+    async fn stamps(files: &[PathBuf]) -> io::Result<Vec<FileStamp>> {
+        let mut stamps = Vec::with_capacity(files.len());
+        for path in files {
+            let (size, mtime) = match fs::metadata(path).await {
+                Ok(metadata) => (
+                    metadata.len(),
+                    metadata
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+                Err(e) => return Err(e),
+            };
+            stamps.push(FileStamp {
+                path: path.display().to_string(),
+                size,
+                mtime,
+            });
+        }
+        Ok(stamps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::torrent_file::TorrentFile;
+    use crate::request::config::ClientConfig;
+    use crate::request::rate_limit::BandwidthLimits;
+    use crate::request::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn load_accepts_only_what_save_wrote() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi8e4:name6:resume12:piece lengthi4e6:pieces40:\
+              aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee",
+        )
+        .unwrap();
+        let info_hash = torrent.compute_info_hash();
+        let name = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+        let name = name.to_str().unwrap();
+        std::fs::write(name, b"00001111").unwrap();
+        let resume = ResumeFile::new(name, vec![PathBuf::from(name)]);
+        assert!(matches!(resume.load(info_hash, 2).await, Resume::Stale(_)));
+
+        let state = TorrentState::new(
+            torrent,
+            [1; 20],
+            ClientConfig::default(),
+            Bitfield::new(2),
+            Box::new(MemoryStorage::new(8, 4)),
+            BandwidthLimits::unlimited(),
+        );
+        state.piece_completed(1);
+        resume.save(&state).await.unwrap();
+        let Resume::Valid(data) = resume.load(info_hash, 2).await else {
+            panic!("resume data just saved is not valid");
+        };
+        assert_eq!(
+            Bitfield::from_bytes(&data.pieces, 2).difference(&Bitfield::new(2)),
+            vec![1]
+        );
+        assert!(matches!(resume.load([0; 20], 2).await, Resume::Stale(_)));
+        assert!(matches!(resume.load(info_hash, 3).await, Resume::Stale(_)));
+
+        //a torn write leaves garbage behind
+        std::fs::write(format!("{}.resume", name), b"d4:info").unwrap();
+        assert!(matches!(resume.load(info_hash, 2).await, Resume::Stale(_)));
+
+        resume.save(&state).await.unwrap();
+        std::fs::write(name, b"000011112222").unwrap();
+        assert!(matches!(resume.load(info_hash, 2).await, Resume::Stale(_)));
+
+        std::fs::remove_file(name).unwrap();
+        std::fs::remove_file(format!("{}.resume", name)).unwrap();
+        assert!(matches!(resume.load(info_hash, 2).await, Resume::Missing));
+    }
+
+    #[tokio::test]
+    async fn half_finished_pieces_survive_a_restart() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi65536e4:name7:partial12:piece lengthi32768e6:pieces40:\
+              aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee",
+        )
+        .unwrap();
+        let info_hash = torrent.compute_info_hash();
+        let name = std::env::temp_dir().join(format!("partial-{}", std::process::id()));
+        let resume = ResumeFile::new(&name, Vec::new());
+        let state = TorrentState::new(
+            torrent.clone(),
+            [1; 20],
+            ClientConfig::default(),
+            Bitfield::new(2),
+            Box::new(MemoryStorage::new(65536, 32768)),
+            BandwidthLimits::unlimited(),
+        );
+        let second = ReceivedBlock {
+            data: vec![7; BLOCK_LENGTH as usize],
+            from: Some([10, 0, 0, 1].into()),
+        };
+        state.give_back(1, vec![None, Some(second.clone())]);
+        //listed only once the blocks are on disk
+        resume.save(&state).await.unwrap();
+        let Resume::Valid(data) = resume.load(info_hash, 2).await else {
+            panic!("resume data just saved is not valid");
+        };
+        assert!(data.partial.is_empty());
+        state.store_unfinished().await.unwrap();
+        resume.save(&state).await.unwrap();
+
+        let Resume::Valid(data) = resume.load(info_hash, 2).await else {
+            panic!("resume data just saved is not valid");
+        };
+        assert_eq!(
+            data.partial,
+            vec![PartialPiece {
+                index: 1,
+                blocks: vec![0b0100_0000]
+            }]
+        );
+        let mut storage = state.storage().lock().await;
+        let blocks = data.partial[0].read(storage.as_mut(), 32768).await.unwrap();
+        assert_eq!(
+            blocks,
+            vec![
+                None,
+                Some(ReceivedBlock {
+                    from: None,
+                    ..second
+                })
+            ]
+        );
+        std::fs::remove_file(format!("{}.resume", name.display())).unwrap();
+    }
+}
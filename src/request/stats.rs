@@ -0,0 +1,122 @@
+use crate::request::choker::ChokerDecision;
+use crate::request::ip_filter::FilterStats;
+use crate::request::peer_pool::PoolStats;
+use crate::request::rate_limit::BandwidthLimits;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone)]
+pub struct PeerSnapshot {
+    pub id: usize,
+    pub address: SocketAddr,
+    pub peer_id: [u8; 20],
+    pub connected_at: Instant,
+    //payload bytes, protocol overhead is not counted
+    pub downloaded: u64,
+    pub uploaded: u64,
+    //bytes per second over the last choker round
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub peer_interested: bool,
+    pub am_interested: bool,
+    pub am_choking: bool,
+    pub optimistic: bool,
+    pub snubbed: bool,
+    pub last_block: Option<Instant>,
+}
+
+//Counters of one connection, written by its PeerStream and read by the choker and the stats.
+pub struct PeerStats {
+    snapshot: Mutex<PeerSnapshot>,
+    //the choker decision, true when we have to choke the peer
+    choke: watch::Sender<bool>,
+    limits: BandwidthLimits,
+}
+
+impl PeerStats {
+    pub fn new(id: usize, address: SocketAddr, peer_id: [u8; 20]) -> Self {
+        let (choke, _) = watch::channel(true);
+        Self {
+            snapshot: Mutex::new(PeerSnapshot {
+                id,
+                address,
+                peer_id,
+                connected_at: Instant::now(),
+                downloaded: 0,
+                uploaded: 0,
+                download_rate: 0.0,
+                upload_rate: 0.0,
+                peer_interested: false,
+                am_interested: false,
+                am_choking: true,
+                optimistic: false,
+                snubbed: false,
+                last_block: None,
+            }),
+            choke,
+            limits: BandwidthLimits::unlimited(),
+        }
+    }
+
+    pub fn snapshot(&self) -> PeerSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.downloaded += bytes;
+        snapshot.last_block = Some(Instant::now());
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.snapshot.lock().unwrap().uploaded += bytes;
+    }
+
+    pub fn set_peer_interested(&self, interested: bool) {
+        self.snapshot.lock().unwrap().peer_interested = interested;
+    }
+
+    pub fn set_am_interested(&self, interested: bool) {
+        self.snapshot.lock().unwrap().am_interested = interested;
+    }
+
+    pub(crate) fn update(&self, f: impl FnOnce(&mut PeerSnapshot)) {
+        f(&mut self.snapshot.lock().unwrap());
+    }
+
+    pub fn set_choke(&self, choke: bool) {
+        self.snapshot.lock().unwrap().am_choking = choke;
+        self.choke.send_if_modified(|current| {
+            let changed = *current != choke;
+            *current = choke;
+            changed
+        });
+    }
+
+    pub fn limits(&self) -> &BandwidthLimits {
+        &self.limits
+    }
+
+    pub fn subscribe_choke(&self) -> watch::Receiver<bool> {
+        self.choke.subscribe()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStats {
+    pub pieces_completed: usize,
+    pub pieces_total: usize,
+    pub uploaded: u64,
+    pub peers: Vec<PeerSnapshot>,
+    pub pool: PoolStats,
+    //blocked counters are shared by the torrents using the same filter
+    pub ip_filter: FilterStats,
+    //outcome of the last choker round, None before the first one
+    pub choker: Option<ChokerDecision>,
+    //pieces can't be written until the disk has room again
+    pub disk_full: bool,
+    //the data couldn't be opened again after a failed move, the torrent waits for another move
+    pub storage_error: Option<String>,
+}
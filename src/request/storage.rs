@@ -1,77 +1,666 @@
-use log::debug;
-use std::collections::{HashMap, HashSet};
-use tokio::fs::{File, OpenOptions, read_to_string};
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use futures::future::BoxFuture;
+use memmap2::MmapMut;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 
-pub struct TorrentPersisted {
+//the hashing part of Storage::check_piece, it doesn't hold the storage
+pub type PieceCheck = BoxFuture<'static, io::Result<bool>>;
+
+//Where the pieces of a torrent are kept. Blocks are addressed by piece index and offset inside
+//the piece, so a backend is free to lay them out as it likes. Applications pass their own
+//backend to Client::with_storage.
+pub trait Storage: Send {
+    fn read_block(
+        &mut self,
+        piece: usize,
+        begin: usize,
+        length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>>;
+
+    fn write_block<'a>(
+        &'a mut self,
+        piece: usize,
+        begin: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    //the blocks written so far survive a crash once this returns
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>>;
+
+    //Whether the length bytes of the piece hash to hash. The storage is only needed until the
+    //check is returned, the check itself hashes on the blocking pool, so several pieces can be
+    //hashed at once.
+    fn check_piece(
+        &mut self,
+        piece: usize,
+        length: usize,
+        hash: [u8; 20],
+    ) -> BoxFuture<'_, io::Result<PieceCheck>> {
+        Box::pin(async move {
+            let data = self.read_block(piece, 0, length).await?;
+            let check: PieceCheck = Box::pin(async move {
+                tokio::task::spawn_blocking(move || Sha1::digest(&data)[..] == hash)
+                    .await
+                    .map_err(io::Error::other)
+            });
+            Ok(check)
+        })
+    }
+
+    //nothing is read or written after this
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>>;
+}
+
+//The backend of the storage the client opens itself, when none is given to Client::with_storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageBackend {
+    //reads and writes through the file
+    #[default]
+    File,
+    //reads and writes through a memory mapping of the file, for local SSDs. The file is always
+    //fully allocated, whatever the preallocation
+    Mmap,
+}
+
+//How the file is sized when it is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Preallocation {
+    //set to its full length, the filesystem allocates blocks only when they are written
+    #[default]
+    Sparse,
+    //every block allocated up front, when the disk has room for them
+    Full,
+    //left as it is, the file grows as pieces are written
+    None,
+}
+
+//Sizes file to total_size as mode asks. Without fallocate the missing tail is written with zeros.
+pub async fn preallocate(file: &mut File, total_size: u64, mode: Preallocation) -> io::Result<()> {
+    match mode {
+        Preallocation::None => Ok(()),
+        Preallocation::Sparse => file.set_len(total_size).await,
+        Preallocation::Full => {
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+            {
+                use std::os::fd::AsRawFd;
+                let fd = file.as_raw_fd();
+                let length = total_size as libc::off_t;
+                //SAFETY: the file, and so the descriptor, outlives the call since we wait for it
+                let result = tokio::task::spawn_blocking(move || unsafe {
+                    libc::posix_fallocate(fd, 0, length)
+                })
+                .await
+                .map_err(io::Error::other)?;
+                match result {
+                    0 => return Ok(()),
+                    libc::ENOSPC => return Err(io::Error::from_raw_os_error(result)),
+                    //the filesystem can't do it, zeros do the same
+                    _ => (),
+                }
+            }
+            let mut length = file.metadata().await?.len();
+            file.seek(SeekFrom::Start(length)).await?;
+            let zeros = vec![0u8; 1024 * 1024];
+            while length < total_size {
+                let chunk = (total_size - length).min(zeros.len() as u64) as usize;
+                file.write_all(&zeros[..chunk]).await?;
+                length += chunk as u64;
+            }
+            file.sync_data().await
+        }
+    }
+}
+
+//Opens file_name with backend. Without room for what is still to allocate, the file backend
+//opens it sparse: writes fail with StorageFull once the disk fills up and the write cache waits
+//for room. The mapping can't start like that, it gets the file backend until the next start.
+pub async fn open_file(
+    file_name: impl AsRef<Path>,
+    total_size: u64,
+    piece_length: usize,
+    backend: StorageBackend,
+    preallocation: Preallocation,
+    room: bool,
+) -> io::Result<Box<dyn Storage>> {
+    Ok(match backend {
+        StorageBackend::Mmap if room => {
+            Box::new(MmapStorage::new(file_name, total_size, piece_length).await?)
+        }
+        StorageBackend::File if room => {
+            Box::new(FileStorage::new(file_name, total_size, piece_length, preallocation).await?)
+        }
+        _ => {
+            let preallocation = match preallocation {
+                Preallocation::Full => Preallocation::Sparse,
+                mode => mode,
+            };
+            Box::new(FileStorage::new(file_name, total_size, piece_length, preallocation).await?)
+        }
+    })
+}
+
+//The file of a torrent whose files are all skipped. Nothing is created on disk until a piece is
+//written after a priority change, every piece reads as missing until then.
+pub struct DeferredFile {
+    path: PathBuf,
+    total_size: u64,
+    piece_length: usize,
+    backend: StorageBackend,
+    preallocation: Preallocation,
+    opened: Option<Box<dyn Storage>>,
+}
+
+impl DeferredFile {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        total_size: u64,
+        piece_length: usize,
+        backend: StorageBackend,
+        preallocation: Preallocation,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            total_size,
+            piece_length,
+            backend,
+            preallocation,
+            opened: None,
+        }
+    }
+}
+
+impl Storage for DeferredFile {
+    fn read_block(
+        &mut self,
+        piece: usize,
+        begin: usize,
+        length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        match &mut self.opened {
+            Some(storage) => storage.read_block(piece, begin, length),
+            None => Box::pin(async { Err(io::ErrorKind::UnexpectedEof.into()) }),
+        }
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        piece: usize,
+        begin: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            if self.opened.is_none() {
+                let (needed, available) = disk_space(&self.path, self.total_size)?;
+                let storage = open_file(
+                    &self.path,
+                    self.total_size,
+                    self.piece_length,
+                    self.backend,
+                    self.preallocation,
+                    needed <= available,
+                )
+                .await?;
+                self.opened = Some(storage);
+            }
+            let storage = self.opened.as_mut().unwrap();
+            storage.write_block(piece, begin, data).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        match &mut self.opened {
+            Some(storage) => storage.flush(),
+            None => Box::pin(async { Ok(()) }),
+        }
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        match &mut self.opened {
+            Some(storage) => storage.close(),
+            None => Box::pin(async { Ok(()) }),
+        }
+    }
+}
+
+//Bytes still to allocate for file_name to reach total_size, and bytes free on its filesystem.
+//The free space is unknown, u64::MAX, where statvfs is missing.
+pub fn disk_space(file_name: impl AsRef<Path>, total_size: u64) -> io::Result<(u64, u64)> {
+    let path = file_name.as_ref();
+    let allocated = match std::fs::metadata(path) {
+        #[cfg(unix)]
+        Ok(metadata) => std::os::unix::fs::MetadataExt::blocks(&metadata) * 512,
+        #[cfg(not(unix))]
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok((
+        total_size.saturating_sub(allocated),
+        available_space(directory)?,
+    ))
+}
+
+#[cfg(unix)]
+fn available_space(directory: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    //SAFETY: path is a valid C string and statvfs fills stat when it returns 0
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_directory: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+//The torrent content in one file, the pieces one after the other.
+pub struct FileStorage {
     file: File,
-    file_name: String,
-    checkpoint_file: File,
+    piece_length: usize,
 }
 
-impl TorrentPersisted {
-    pub async fn new(file_name: &str, total_size: u64) -> std::io::Result<Self> {
-        let file = OpenOptions::new()
+impl FileStorage {
+    pub async fn new(
+        file_name: impl AsRef<Path>,
+        total_size: u64,
+        piece_length: usize,
+        preallocation: Preallocation,
+    ) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(file_name)
             .await?;
 
-        file.set_len(total_size).await?;
+        preallocate(&mut file, total_size, preallocation).await?;
+        Ok(Self { file, piece_length })
+    }
+
+    fn offset(&self, piece: usize, begin: usize) -> u64 {
+        piece as u64 * self.piece_length as u64 + begin as u64
+    }
+}
+
+impl Storage for FileStorage {
+    fn read_block(
+        &mut self,
+        piece: usize,
+        begin: usize,
+        length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let offset = self.offset(piece, begin);
+        Box::pin(async move {
+            let mut block = vec![0u8; length];
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.file.read_exact(&mut block).await?;
+            Ok(block)
+        })
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        piece: usize,
+        begin: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let offset = self.offset(piece, begin);
+        Box::pin(async move {
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.file.write_all(data).await
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async move {
+            self.file.flush().await?;
+            self.file.sync_data().await
+        })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.flush()
+    }
+}
+
+//The torrent content in one memory mapped file. Blocks are copied in and out of the mapping
+//instead of a seek and a read or write call each, and pieces are hashed in place. Every block of
+//the file is allocated when it is opened: a write through the mapping can't fail, a hole on a
+//full disk would be a SIGBUS instead of an error.
+pub struct MmapStorage {
+    //pieces are hashed in parallel, only writes need the mapping alone
+    map: Arc<RwLock<MmapMut>>,
+    piece_length: usize,
+}
 
-        let checkpoint_file = OpenOptions::new()
+impl MmapStorage {
+    pub async fn new(
+        file_name: impl AsRef<Path>,
+        total_size: u64,
+        piece_length: usize,
+    ) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
             .read(true)
-            .append(true)
             .write(true)
             .create(true)
-            .open(format!("{}.{}", file_name, "checkpoint"))
+            .truncate(false)
+            .open(file_name)
             .await?;
-
-        //fixme unwrap ?
+        preallocate(&mut file, total_size, Preallocation::Full).await?;
+        let file = file.into_std().await;
+        //SAFETY: the mapping goes wrong if the file shrinks under it, we own the file for the
+        //whole download just like FileStorage does
+        let map = unsafe { MmapMut::map_mut(&file)? };
         Ok(Self {
-            file,
-            file_name: file_name.parse().unwrap(),
-            checkpoint_file,
+            map: Arc::new(RwLock::new(map)),
+            piece_length,
         })
     }
 
-    pub async fn read_checkpoint(&self) -> std::io::Result<HashSet<usize>> {
-        let path = format!("{}.checkpoint", self.file_name);
-
-        if !std::path::Path::new(&path).exists() {
-            return Ok(HashSet::new());
+    fn range(&self, piece: usize, begin: usize, length: usize) -> io::Result<(usize, usize)> {
+        let start = piece * self.piece_length + begin;
+        let end = start + length;
+        if end > self.map.read().unwrap().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "block past the end of the torrent",
+            ));
         }
+        Ok((start, end))
+    }
+}
 
-        let content = read_to_string(path).await?;
+impl Storage for MmapStorage {
+    fn read_block(
+        &mut self,
+        piece: usize,
+        begin: usize,
+        length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let block = self
+            .range(piece, begin, length)
+            .map(|(start, end)| self.map.read().unwrap()[start..end].to_vec());
+        Box::pin(async move { block })
+    }
 
-        let completed_pieces: HashSet<usize> = content
-            .split(',')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .filter_map(|s| s.parse::<usize>().ok())
-            .collect();
+    fn write_block<'a>(
+        &'a mut self,
+        piece: usize,
+        begin: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let written = self
+            .range(piece, begin, data.len())
+            .map(|(start, end)| self.map.write().unwrap()[start..end].copy_from_slice(data));
+        Box::pin(async move { written })
+    }
 
-        Ok(completed_pieces)
+    //msync waits for the disk, it runs on the blocking pool
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let map = Arc::clone(&self.map);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || map.read().unwrap().flush())
+                .await
+                .map_err(io::Error::other)?
+        })
     }
 
-    pub async fn write_pieces(
+    fn check_piece(
         &mut self,
-        data: &mut HashMap<usize, Vec<u8>>,
-        piece_length: usize,
-    ) -> std::io::Result<()> {
-        let mut piece_id: Vec<u32> = vec![];
-        for (i, piece) in data.drain() {
-            let offset = (i as u64) * (piece_length as u64);
-            self.file.seek(SeekFrom::Start(offset)).await?;
-            self.file.write_all(&piece).await?;
-            piece_id.push(i as u32);
+        piece: usize,
+        length: usize,
+        hash: [u8; 20],
+    ) -> BoxFuture<'_, io::Result<PieceCheck>> {
+        let range = self.range(piece, 0, length);
+        let map = Arc::clone(&self.map);
+        Box::pin(async move {
+            //hashed in place, nothing is copied
+            let (start, end) = range?;
+            let check: PieceCheck = Box::pin(async move {
+                tokio::task::spawn_blocking(move || {
+                    Sha1::digest(&map.read().unwrap()[start..end])[..] == hash
+                })
+                .await
+                .map_err(io::Error::other)
+            });
+            Ok(check)
+        })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        self.flush()
+    }
+}
+
+//The torrent content in memory, for tests. Clones share the same bytes, so a clone kept aside
+//shows what the client wrote.
+#[derive(Clone)]
+pub struct MemoryStorage {
+    data: Arc<Mutex<Vec<u8>>>,
+    piece_length: usize,
+}
+
+impl MemoryStorage {
+    pub fn new(total_size: usize, piece_length: usize) -> Self {
+        Self::with_data(vec![0; total_size], piece_length)
+    }
+
+    pub fn with_data(data: Vec<u8>, piece_length: usize) -> Self {
+        Self {
+            data: Arc::new(Mutex::new(data)),
+            piece_length,
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    fn range(&self, piece: usize, begin: usize, length: usize) -> io::Result<(usize, usize)> {
+        let start = piece * self.piece_length + begin;
+        let end = start + length;
+        if end > self.data.lock().unwrap().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "block past the end of the torrent",
+            ));
         }
+        Ok((start, end))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(
+        &mut self,
+        piece: usize,
+        begin: usize,
+        length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let block = self
+            .range(piece, begin, length)
+            .map(|(start, end)| self.data.lock().unwrap()[start..end].to_vec());
+        Box::pin(async move { block })
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        piece: usize,
+        begin: usize,
+        data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let written = self
+            .range(piece, begin, data.len())
+            .map(|(start, end)| self.data.lock().unwrap()[start..end].copy_from_slice(data));
+        Box::pin(async move { written })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+//Stands in for data that couldn't be opened again after a failed move. Every read and write fails
+//with the reason until the data is moved somewhere it opens.
+pub struct FailedStorage {
+    reason: String,
+}
+
+impl FailedStorage {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::other(self.reason.clone())
+    }
+}
+
+impl Storage for FailedStorage {
+    fn read_block(
+        &mut self,
+        _piece: usize,
+        _begin: usize,
+        _length: usize,
+    ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        let error = self.error();
+        Box::pin(async move { Err(error) })
+    }
+
+    fn write_block<'a>(
+        &'a mut self,
+        _piece: usize,
+        _begin: usize,
+        _data: &'a [u8],
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let error = self.error();
+        Box::pin(async move { Err(error) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(storage: &mut dyn Storage) {
+        storage.write_block(1, 2, b"block").await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(storage.read_block(1, 2, 5).await.unwrap(), b"block");
+        let piece = storage.read_block(1, 0, 8).await.unwrap();
+        let hash: [u8; 20] = Sha1::digest(&piece).into();
+        let check = storage.check_piece(1, 8, hash).await.unwrap();
+        assert!(check.await.unwrap());
+        let check = storage.check_piece(0, 8, hash).await.unwrap();
+        assert!(!check.await.unwrap());
+        storage.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn backends_read_what_they_wrote() {
+        let path = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        let mut file = FileStorage::new(path.to_str().unwrap(), 20, 8, Preallocation::Full)
+            .await
+            .unwrap();
+        round_trip(&mut file).await;
+        drop(file);
+
+        //the mapping sees what the file backend wrote, and the file what the mapping wrote
+        let mut mapped = MmapStorage::new(path.to_str().unwrap(), 20, 8)
+            .await
+            .unwrap();
+        assert_eq!(mapped.read_block(1, 2, 5).await.unwrap(), b"block");
+        mapped.write_block(0, 0, b"mapped").await.unwrap();
+        round_trip(&mut mapped).await;
+        drop(mapped);
+        assert_eq!(&std::fs::read(&path).unwrap()[..6], b"mapped");
+        std::fs::remove_file(path).unwrap();
+
+        let memory = MemoryStorage::new(20, 8);
+        round_trip(&mut memory.clone()).await;
+        assert_eq!(&memory.contents()[10..15], b"block");
+        assert!(memory.clone().write_block(2, 0, &[0; 8]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deferred_files_are_created_by_the_first_write() {
+        let path = std::env::temp_dir().join(format!("deferred-{}", std::process::id()));
+        let mut deferred =
+            DeferredFile::new(&path, 20, 8, StorageBackend::File, Preallocation::Full);
+        let missing = deferred.read_block(1, 0, 8).await;
+        assert!(matches!(missing, Err(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+        deferred.flush().await.unwrap();
+        assert!(!path.exists());
+
+        round_trip(&mut deferred).await;
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 20);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mapped_files_are_allocated_or_not_mapped() {
+        use std::os::unix::fs::MetadataExt;
+        let total = 1024 * 1024;
+        let allocated = |path: &Path| std::fs::metadata(path).unwrap().blocks() * 512;
+        let path = std::env::temp_dir().join(format!("mapped-{}", std::process::id()));
+        let storage = open_file(
+            &path,
+            total,
+            4096,
+            StorageBackend::Mmap,
+            Preallocation::None,
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(allocated(&path) >= total);
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
 
-        self.file.sync_data().await?;
-        let data: String = piece_id.iter().map(|id| format!("{},", id)).collect();
-        self.checkpoint_file.write_all(data.as_bytes()).await?;
-        debug!("Flushed downloaded pieces to storage");
-        Ok(())
+        //short of space the file is opened sparse and the first write tells if it fits
+        let mut storage = open_file(
+            &path,
+            total,
+            4096,
+            StorageBackend::Mmap,
+            Preallocation::Full,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(allocated(&path) < total);
+        storage.write_block(3, 0, b"block").await.unwrap();
+        assert_eq!(storage.read_block(3, 0, 5).await.unwrap(), b"block");
+        drop(storage);
+        std::fs::remove_file(path).unwrap();
     }
 }
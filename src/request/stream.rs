@@ -0,0 +1,265 @@
+use crate::request::torrent_state::TorrentState;
+use futures::future::BoxFuture;
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+//pieces after the one being read that are asked for at the same priority
+const READAHEAD: usize = 4;
+
+//One file of a torrent, readable while it downloads. A read raises the pieces around the position
+//to High priority and waits until the first one is verified and written; seeking moves them.
+//Once the download has ended, reading a piece that was skipped is an error.
+pub struct TorrentReader {
+    state: Arc<TorrentState>,
+    //every reader has its own window of High pieces
+    id: usize,
+    //bytes of the file within the torrent data
+    file: Range<usize>,
+    position: u64,
+    readahead: usize,
+    reading: Option<BoxFuture<'static, io::Result<Vec<u8>>>>,
+}
+
+impl TorrentReader {
+    //None if the torrent has no such file
+    pub fn new(state: Arc<TorrentState>, file: usize) -> Option<Self> {
+        let file = state.info().file_ranges().get(file)?.clone();
+        Some(Self {
+            id: state.next_reader_id(),
+            state,
+            file,
+            position: 0,
+            readahead: READAHEAD,
+            reading: None,
+        })
+    }
+
+    pub fn with_readahead(mut self, pieces: usize) -> Self {
+        self.readahead = pieces;
+        self
+    }
+
+    //the piece holding offset and the readahead after it, within the file
+    fn window(
+        state: &TorrentState,
+        file: &Range<usize>,
+        offset: usize,
+        readahead: usize,
+    ) -> Range<usize> {
+        let info = state.info();
+        let last = file.end.div_ceil(info.piece_length);
+        let first = offset / info.piece_length;
+        first..(first + 1 + readahead).min(last)
+    }
+
+    //at most the end of the piece holding offset
+    async fn read_at(
+        state: Arc<TorrentState>,
+        reader: usize,
+        window: Range<usize>,
+        offset: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let piece = window.start;
+        state.set_streaming_window(reader, window);
+        //subscribe before looking, a piece completed in between would be missed
+        let mut completed = state.subscribe();
+        while !state.has_piece(piece) {
+            if state.download_ended() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the piece was skipped and the download has ended",
+                ));
+            }
+            completed
+                .changed()
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        let info = state.info();
+        let begin = offset - piece * info.piece_length;
+        let length = length.min(info.piece_size(piece) - begin);
+        state
+            .storage()
+            .lock()
+            .await
+            .read_block(piece, begin, length)
+            .await
+    }
+}
+
+impl AsyncRead for TorrentReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.reading.is_none() {
+            let offset = this.file.start as u64 + this.position;
+            if offset >= this.file.end as u64 || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let offset = offset as usize;
+            let length = buf.remaining().min(this.file.end - offset);
+            let window = Self::window(&this.state, &this.file, offset, this.readahead);
+            this.reading = Some(Box::pin(Self::read_at(
+                Arc::clone(&this.state),
+                this.id,
+                window,
+                offset,
+                length,
+            )));
+        }
+        let read = ready!(this.reading.as_mut().unwrap().as_mut().poll(cx));
+        this.reading = None;
+        let data = read?;
+        //the buffer may have shrunk since the read started, the rest is read again next time
+        let length = data.len().min(buf.remaining());
+        buf.put_slice(&data[..length]);
+        this.position += length as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for TorrentReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let length = (self.file.end - self.file.start) as u64;
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        self.position = position;
+        self.reading = None;
+        //start on the new pieces before the next read asks for them
+        let offset = self.file.start as u64 + position;
+        if offset < self.file.end as u64 {
+            let window = Self::window(&self.state, &self.file, offset as usize, self.readahead);
+            self.state.set_streaming_window(self.id, window);
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for TorrentReader {
+    fn drop(&mut self) {
+        self.state.set_streaming_window(self.id, 0..0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::torrent_file::TorrentFile;
+    use crate::request::bitfield::Bitfield;
+    use crate::request::config::ClientConfig;
+    use crate::request::piece_picker::Priority;
+    use crate::request::rate_limit::BandwidthLimits;
+    use crate::request::storage::MemoryStorage;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn read_waits_for_the_pieces_it_raised() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi12e4:name6:stream12:piece lengthi4e6:pieces60:\
+              aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee",
+        )
+        .unwrap();
+        let mut bitfield = Bitfield::new(3);
+        bitfield.set(0);
+        let state = Arc::new(TorrentState::new(
+            torrent,
+            [1; 20],
+            ClientConfig::default(),
+            bitfield,
+            Box::new(MemoryStorage::with_data(b"000011112222".to_vec(), 4)),
+            BandwidthLimits::unlimited(),
+        ));
+        let mut reader = TorrentReader::new(Arc::clone(&state), 0)
+            .unwrap()
+            .with_readahead(0);
+        reader.seek(SeekFrom::Start(5)).await.unwrap();
+        assert_eq!(state.picker().priority(1), Priority::High);
+        assert_eq!(state.picker().priority(2), Priority::Normal);
+
+        let mut data = [0; 4];
+        {
+            let read = reader.read_exact(&mut data);
+            tokio::pin!(read);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), &mut read)
+                    .await
+                    .is_err()
+            );
+            state.piece_completed(1);
+            state.piece_completed(2);
+            read.await.unwrap();
+        }
+        assert_eq!(&data, b"1112");
+        assert_eq!(state.picker().priority(1), Priority::Normal);
+        assert_eq!(state.picker().priority(2), Priority::High);
+
+        //a second reader on the same piece keeps it High when the first one leaves
+        let mut other = TorrentReader::new(Arc::clone(&state), 0)
+            .unwrap()
+            .with_readahead(0);
+        other.seek(SeekFrom::Start(9)).await.unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"222");
+        drop(reader);
+        assert_eq!(state.picker().priority(2), Priority::High);
+        drop(other);
+        assert_eq!(state.picker().priority(2), Priority::Normal);
+    }
+
+    #[tokio::test]
+    async fn skipped_pieces_cannot_be_read_once_the_download_ended() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi8e4:name6:stream12:piece lengthi4e6:pieces40:\
+              aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee",
+        )
+        .unwrap();
+        let mut bitfield = Bitfield::new(2);
+        bitfield.set(0);
+        let state = Arc::new(TorrentState::new(
+            torrent,
+            [1; 20],
+            ClientConfig::default(),
+            bitfield,
+            Box::new(MemoryStorage::with_data(b"00001111".to_vec(), 4)),
+            BandwidthLimits::unlimited(),
+        ));
+        let mut reader = TorrentReader::new(Arc::clone(&state), 0)
+            .unwrap()
+            .with_readahead(0);
+        let mut data = Vec::new();
+        let read = reader.read_to_end(&mut data);
+        tokio::pin!(read);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut read)
+                .await
+                .is_err()
+        );
+        state.set_download_ended();
+        let error = read.await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(data, b"0000");
+    }
+}
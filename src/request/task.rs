@@ -0,0 +1,10 @@
+use tokio::task::JoinHandle;
+
+//background task that lives as long as its owner
+pub struct AbortOnDrop(pub JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
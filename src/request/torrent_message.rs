@@ -1,3 +1,5 @@
+use crate::request::client::ClientError;
+
 #[derive(Debug, PartialEq)]
 enum MessageID {
     Choke = 0,
@@ -10,6 +12,12 @@ enum MessageID {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -17,6 +25,11 @@ pub enum TorrentMessage {
     KeepAlive,
     Choke,
     Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
     Bitfield {
         bitfield: Vec<u8>,
     },
@@ -30,52 +43,192 @@ pub enum TorrentMessage {
         begin: u32,
         length: u32,
     },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port {
+        port: u16,
+    },
+    //BEP 6 fast extension, only sent when both handshakes have the fast bit
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    //BEP 10, id 0 is the extended handshake, the payload is a bencoded dictionary
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+fn read_u32(input_stream: &[u8], start: usize) -> Result<u32, ClientError> {
+    input_stream
+        .get(start..start + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| {
+            ClientError::MalformedMessage(format!("message id {} too short", input_stream[0]))
+        })
 }
 
 impl TorrentMessage {
-    pub fn read(input_stream: &[u8]) -> TorrentMessage {
-        if input_stream.len() == 0 {
-            return TorrentMessage::KeepAlive;
+    pub fn read(input_stream: &[u8]) -> Result<TorrentMessage, ClientError> {
+        if input_stream.is_empty() {
+            return Ok(TorrentMessage::KeepAlive);
         }
 
-        match input_stream[0] {
+        let message = match input_stream[0] {
             0 => TorrentMessage::Choke,
             1 => TorrentMessage::Unchoke,
+            2 => TorrentMessage::Interested,
+            3 => TorrentMessage::NotInterested,
+            4 => TorrentMessage::Have {
+                index: read_u32(input_stream, 1)?,
+            },
             5 => TorrentMessage::Bitfield {
                 bitfield: input_stream[1..].to_vec(),
             },
+            6 => TorrentMessage::Request {
+                index: read_u32(input_stream, 1)?,
+                begin: read_u32(input_stream, 5)?,
+                length: read_u32(input_stream, 9)?,
+            },
             7 => TorrentMessage::Piece {
-                index: u32::from_be_bytes(input_stream[1..5].try_into().unwrap()),
-                begin: u32::from_be_bytes(input_stream[5..9].try_into().unwrap()),
+                index: read_u32(input_stream, 1)?,
+                begin: read_u32(input_stream, 5)?,
                 block: input_stream[9..].to_vec(),
             },
-            _ => panic!("invalid torrent message received"), //FIXME
-        }
+            8 => TorrentMessage::Cancel {
+                index: read_u32(input_stream, 1)?,
+                begin: read_u32(input_stream, 5)?,
+                length: read_u32(input_stream, 9)?,
+            },
+            9 => match input_stream.get(1..3) {
+                Some(port) => TorrentMessage::Port {
+                    port: u16::from_be_bytes(port.try_into().unwrap()),
+                },
+                None => return Err(ClientError::MalformedMessage("port too short".to_string())),
+            },
+            13 => TorrentMessage::SuggestPiece {
+                index: read_u32(input_stream, 1)?,
+            },
+            14 => TorrentMessage::HaveAll,
+            15 => TorrentMessage::HaveNone,
+            16 => TorrentMessage::RejectRequest {
+                index: read_u32(input_stream, 1)?,
+                begin: read_u32(input_stream, 5)?,
+                length: read_u32(input_stream, 9)?,
+            },
+            17 => TorrentMessage::AllowedFast {
+                index: read_u32(input_stream, 1)?,
+            },
+            20 => match input_stream.get(1) {
+                Some(id) => TorrentMessage::Extended {
+                    id: *id,
+                    payload: input_stream[2..].to_vec(),
+                },
+                None => {
+                    return Err(ClientError::MalformedMessage(
+                        "extended without id".to_string(),
+                    ));
+                }
+            },
+            id => {
+                return Err(ClientError::MalformedMessage(format!(
+                    "unknown message id {}",
+                    id
+                )));
+            }
+        };
+        Ok(message)
+    }
+
+    fn frame(id: MessageID, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5 + payload.len());
+        // length prefix, id + payload
+        out.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+        out.push(id as u8);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn block_payload(index: u32, begin: u32, length: u32) -> [u8; 12] {
+        let mut payload = [0u8; 12];
+        payload[0..4].copy_from_slice(&index.to_be_bytes());
+        payload[4..8].copy_from_slice(&begin.to_be_bytes());
+        payload[8..12].copy_from_slice(&length.to_be_bytes());
+        payload
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
+            TorrentMessage::KeepAlive => 0u32.to_be_bytes().to_vec(),
+            TorrentMessage::Choke => Self::frame(MessageID::Choke, &[]),
+            TorrentMessage::Unchoke => Self::frame(MessageID::Unchoke, &[]),
+            TorrentMessage::Interested => Self::frame(MessageID::Interested, &[]),
+            TorrentMessage::NotInterested => Self::frame(MessageID::NotInterested, &[]),
+            TorrentMessage::Have { index } => Self::frame(MessageID::Have, &index.to_be_bytes()),
+            TorrentMessage::Bitfield { bitfield } => Self::frame(MessageID::Bitfield, bitfield),
             TorrentMessage::Request {
                 index,
                 begin,
                 length,
+            } => Self::frame(
+                MessageID::Request,
+                &Self::block_payload(*index, *begin, *length),
+            ),
+            TorrentMessage::Piece {
+                index,
+                begin,
+                block,
             } => {
-                let mut out = Vec::with_capacity(17);
-
-                // length prefix  13
-                out.extend_from_slice(&13u32.to_be_bytes());
-
-                // message id
-                out.push(MessageID::Request as u8);
-
-                // payload
-                out.extend_from_slice(&index.to_be_bytes());
-                out.extend_from_slice(&begin.to_be_bytes());
-                out.extend_from_slice(&length.to_be_bytes());
-
-                out
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+                Self::frame(MessageID::Piece, &payload)
+            }
+            TorrentMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => Self::frame(
+                MessageID::Cancel,
+                &Self::block_payload(*index, *begin, *length),
+            ),
+            TorrentMessage::Port { port } => Self::frame(MessageID::Port, &port.to_be_bytes()),
+            TorrentMessage::SuggestPiece { index } => {
+                Self::frame(MessageID::SuggestPiece, &index.to_be_bytes())
+            }
+            TorrentMessage::HaveAll => Self::frame(MessageID::HaveAll, &[]),
+            TorrentMessage::HaveNone => Self::frame(MessageID::HaveNone, &[]),
+            TorrentMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => Self::frame(
+                MessageID::RejectRequest,
+                &Self::block_payload(*index, *begin, *length),
+            ),
+            TorrentMessage::AllowedFast { index } => {
+                Self::frame(MessageID::AllowedFast, &index.to_be_bytes())
+            }
+            TorrentMessage::Extended { id, payload } => {
+                let mut extended = Vec::with_capacity(1 + payload.len());
+                extended.push(*id);
+                extended.extend_from_slice(payload);
+                Self::frame(MessageID::Extended, &extended)
             }
-            _ => Vec::new(),
         }
     }
 
@@ -99,10 +252,7 @@ impl TorrentMessage {
                 let byte_index = (index % 8) as u8;
                 let byte = bitfield[byte_num];
                 let mask = 1 << (7 - byte_index);
-                match mask & byte {
-                    0 => false,
-                    _ => true,
-                }
+                mask & byte != 0
             }
             _ => false,
         }
@@ -0,0 +1,441 @@
+use crate::parser::torrent_file::{TorrentFile, TorrentInfo};
+use crate::request::bitfield::Bitfield;
+use crate::request::choker::ChokerDecision;
+use crate::request::client::ClientError;
+use crate::request::config::ClientConfig;
+use crate::request::peer_pool::PeerPool;
+use crate::request::piece_picker::{PiecePicker, Priority};
+use crate::request::rate_limit::BandwidthLimits;
+use crate::request::request_queue::{BLOCK_LENGTH, ReceivedBlock};
+use crate::request::stats::{PeerStats, TorrentStats};
+use crate::request::storage::Storage;
+use crate::request::utp::UtpSocket;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, RwLock};
+use tokio::sync::{Mutex, watch};
+
+//the blocks we have of a piece no peer is downloading
+struct UnfinishedPiece {
+    blocks: Vec<Option<ReceivedBlock>>,
+    //written to storage, the resume data can list them
+    stored: bool,
+}
+
+//state of one torrent shared between the client and all the peer connections
+pub struct TorrentState {
+    torrent_file: TorrentFile,
+    info_hash: [u8; 20],
+    client_peer_id: [u8; 20],
+    config: ClientConfig,
+    bitfield: RwLock<Bitfield>,
+    //number of pieces we have, peers watch it to know when to send Have
+    completed: watch::Sender<usize>,
+    //pieces we miss that no peer is downloading
+    picker: PiecePicker,
+    //by piece, the blocks received before the peer downloading it went away
+    unfinished: StdMutex<HashMap<usize, UnfinishedPiece>>,
+    //one per file of the torrent, the pieces get the highest of the files they hold bytes of
+    file_priorities: StdMutex<Vec<Priority>>,
+    //by reader id, the pieces it is waiting for or about to. A piece in any of them is High
+    //whatever its file priority
+    streaming: StdMutex<HashMap<usize, Range<usize>>>,
+    next_reader_id: AtomicUsize,
+    uploaded: AtomicU64,
+    storage: Mutex<Box<dyn Storage>>,
+    //set while writes fail for lack of space, the write cache fills up and downloading stops
+    disk_full: AtomicBool,
+    //no piece is verified or written anymore, what is missing now stays missing
+    download_ended: AtomicBool,
+    //why the data can't be opened after a failed move, nothing is read or written meanwhile
+    storage_error: StdMutex<Option<String>>,
+    //connections currently open, by peer stream id
+    peers: RwLock<HashMap<usize, Arc<PeerStats>>>,
+    next_connection_id: AtomicUsize,
+    //every address we know, connected or not
+    pool: PeerPool,
+    choker_decision: StdMutex<Option<ChokerDecision>>,
+    //shared with the other torrents of the client
+    global_limits: BandwidthLimits,
+    limits: BandwidthLimits,
+    //outgoing connections try it before TCP, set when uTP is enabled
+    utp: OnceLock<Arc<UtpSocket>>,
+}
+
+impl TorrentState {
+    pub fn new(
+        torrent_file: TorrentFile,
+        client_peer_id: [u8; 20],
+        config: ClientConfig,
+        bitfield: Bitfield,
+        storage: Box<dyn Storage>,
+        global_limits: BandwidthLimits,
+    ) -> Self {
+        let (completed, _) = watch::channel(bitfield.count());
+        let pool = PeerPool::new(config.max_peer_failures, Arc::clone(&config.ip_filter));
+        let limits = BandwidthLimits::new(config.upload_limit, config.download_limit);
+        let picker = PiecePicker::new((0..bitfield.len()).filter(|index| !bitfield.has(*index)))
+            .sequential(config.sequential);
+        let mut file_priorities = config.file_priorities.clone();
+        file_priorities.resize(torrent_file.info.file_ranges().len(), Priority::Normal);
+        let state = Self {
+            info_hash: torrent_file.compute_info_hash(),
+            torrent_file,
+            client_peer_id,
+            config,
+            bitfield: RwLock::new(bitfield),
+            completed,
+            picker,
+            unfinished: StdMutex::new(HashMap::new()),
+            file_priorities: StdMutex::new(file_priorities),
+            streaming: StdMutex::new(HashMap::new()),
+            next_reader_id: AtomicUsize::new(1),
+            uploaded: AtomicU64::new(0),
+            storage: Mutex::new(storage),
+            disk_full: AtomicBool::new(false),
+            download_ended: AtomicBool::new(false),
+            storage_error: StdMutex::new(None),
+            peers: RwLock::new(HashMap::new()),
+            next_connection_id: AtomicUsize::new(1),
+            pool,
+            choker_decision: StdMutex::new(None),
+            global_limits,
+            limits,
+            utp: OnceLock::new(),
+        };
+        let files = state.file_priorities.lock().unwrap().len();
+        for file in 0..files {
+            state.update_piece_priorities(file);
+        }
+        state
+    }
+
+    pub fn pool(&self) -> &PeerPool {
+        &self.pool
+    }
+
+    //id of a new peer stream, incoming and outgoing share the sequence
+    pub fn next_connection_id(&self) -> usize {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn set_utp_socket(&self, socket: Arc<UtpSocket>) {
+        let _ = self.utp.set(socket);
+    }
+
+    pub fn utp_socket(&self) -> Option<&Arc<UtpSocket>> {
+        self.utp.get()
+    }
+
+    pub fn next_reader_id(&self) -> usize {
+        self.next_reader_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn torrent_file(&self) -> &TorrentFile {
+        &self.torrent_file
+    }
+
+    pub fn info(&self) -> &TorrentInfo {
+        &self.torrent_file.info
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn client_peer_id(&self) -> &[u8; 20] {
+        &self.client_peer_id
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn bitfield(&self) -> Bitfield {
+        self.bitfield.read().unwrap().clone()
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.bitfield.read().unwrap().has(index)
+    }
+
+    //the piece must already be readable from storage, since peers can ask for it right away
+    pub fn piece_completed(&self, index: usize) {
+        self.unfinished.lock().unwrap().remove(&index);
+        let count = {
+            let mut bitfield = self.bitfield.write().unwrap();
+            bitfield.set(index);
+            bitfield.count()
+        };
+        self.completed.send_replace(count);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<usize> {
+        self.completed.subscribe()
+    }
+
+    pub fn picker(&self) -> &PiecePicker {
+        &self.picker
+    }
+
+    //the piece goes back to the picker, the blocks received so far wait for the next peer
+    pub fn give_back(&self, index: usize, blocks: Vec<Option<ReceivedBlock>>) {
+        if blocks.iter().any(Option::is_some) {
+            let piece = UnfinishedPiece {
+                blocks,
+                stored: false,
+            };
+            self.unfinished.lock().unwrap().insert(index, piece);
+        }
+        self.picker.put_back(index);
+    }
+
+    //blocks of a piece read back from storage at start
+    pub fn restore_blocks(&self, index: usize, blocks: Vec<Option<ReceivedBlock>>) {
+        let piece = UnfinishedPiece {
+            blocks,
+            stored: true,
+        };
+        self.unfinished.lock().unwrap().insert(index, piece);
+    }
+
+    //the blocks we have of a piece a peer is about to download, empty if none
+    pub fn take_blocks(&self, index: usize) -> Vec<Option<ReceivedBlock>> {
+        self.unfinished
+            .lock()
+            .unwrap()
+            .remove(&index)
+            .map_or_else(Vec::new, |piece| piece.blocks)
+    }
+
+    //Writes the blocks of unfinished pieces that are not on disk yet. They are not verified, but
+    //nobody reads a piece before it is complete and checked.
+    pub async fn store_unfinished(&self) -> io::Result<()> {
+        let pieces: Vec<(usize, Vec<Option<ReceivedBlock>>)> = self
+            .unfinished
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, piece)| !piece.stored)
+            .map(|(index, piece)| (*index, piece.blocks.clone()))
+            .collect();
+        if pieces.is_empty() {
+            return Ok(());
+        }
+        let mut storage = self.storage.lock().await;
+        for (index, blocks) in &pieces {
+            for (block, received) in blocks.iter().enumerate() {
+                if let Some(received) = received {
+                    let begin = block * BLOCK_LENGTH as usize;
+                    storage.write_block(*index, begin, &received.data).await?;
+                }
+            }
+        }
+        storage.flush().await?;
+        drop(storage);
+        //a peer may have taken the piece meanwhile, or given it back with more blocks
+        let mut unfinished = self.unfinished.lock().unwrap();
+        for (index, blocks) in pieces {
+            if let Some(piece) = unfinished.get_mut(&index)
+                && piece.blocks == blocks
+            {
+                piece.stored = true;
+            }
+        }
+        Ok(())
+    }
+
+    //the blocks on disk of every unfinished piece, for the resume data
+    pub fn stored_blocks(&self) -> Vec<(usize, Bitfield)> {
+        let unfinished = self.unfinished.lock().unwrap();
+        let mut stored: Vec<(usize, Bitfield)> = unfinished
+            .iter()
+            .filter(|(_, piece)| piece.stored)
+            .map(|(index, piece)| {
+                let mut blocks = Bitfield::new(piece.blocks.len());
+                for (block, received) in piece.blocks.iter().enumerate() {
+                    if received.is_some() {
+                        blocks.set(block);
+                    }
+                }
+                (*index, blocks)
+            })
+            .collect();
+        stored.sort_by_key(|(index, _)| *index);
+        stored
+    }
+
+    //false if the torrent has no such file; pieces already downloaded are kept either way
+    pub fn set_file_priority(&self, file: usize, priority: Priority) -> bool {
+        match self.file_priorities.lock().unwrap().get_mut(file) {
+            Some(current) => *current = priority,
+            None => return false,
+        }
+        self.update_piece_priorities(file);
+        true
+    }
+
+    //every piece of the torrent is either downloaded or skipped
+    pub fn wanted_pieces_completed(&self, received: &Bitfield) -> bool {
+        (0..received.len())
+            .all(|index| received.has(index) || self.picker.priority(index) == Priority::Skip)
+    }
+
+    //the pieces leaving the window of reader go back to the priority of their files, unless
+    //another reader wants them too; an empty window removes the reader
+    pub fn set_streaming_window(&self, reader: usize, window: Range<usize>) {
+        let priorities = self.piece_priorities();
+        let mut streaming = self.streaming.lock().unwrap();
+        let previous = match window.is_empty() {
+            true => streaming.remove(&reader),
+            false => streaming.insert(reader, window.clone()),
+        }
+        .unwrap_or(0..0);
+        if previous == window {
+            return;
+        }
+        for index in previous {
+            if !streaming.values().any(|window| window.contains(&index)) {
+                self.picker.set_priority(index, priorities(index));
+            }
+        }
+        for index in window {
+            self.picker.set_priority(index, Priority::High);
+        }
+    }
+
+    //a piece shared with a wanted file is downloaded even if this file is skipped
+    fn update_piece_priorities(&self, file: usize) {
+        let Some(pieces) = self.info().file_pieces(file) else {
+            return;
+        };
+        let priorities = self.piece_priorities();
+        let streaming = self.streaming.lock().unwrap();
+        for index in pieces {
+            if !streaming.values().any(|window| window.contains(&index)) {
+                self.picker.set_priority(index, priorities(index));
+            }
+        }
+    }
+
+    //the highest priority among the files a piece holds bytes of
+    fn piece_priorities(&self) -> impl Fn(usize) -> Priority {
+        let info = self.info();
+        let priorities = self.file_priorities.lock().unwrap();
+        let files: Vec<_> = (0..priorities.len())
+            .filter_map(|file| Some((info.file_pieces(file)?, priorities[file])))
+            .collect();
+        move |index| {
+            files
+                .iter()
+                .filter(|(pieces, _)| pieces.contains(&index))
+                .map(|(_, priority)| *priority)
+                .max()
+                .unwrap_or_default()
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn storage(&self) -> &Mutex<Box<dyn Storage>> {
+        &self.storage
+    }
+
+    //a second connection to the same peer id is refused
+    pub fn register_peer(
+        &self,
+        id: usize,
+        address: SocketAddr,
+        peer_id: [u8; 20],
+    ) -> Result<Arc<PeerStats>, ClientError> {
+        let mut peers = self.peers.write().unwrap();
+        if peers
+            .values()
+            .any(|peer| peer.snapshot().peer_id == peer_id)
+        {
+            return Err(ClientError::DuplicatePeer);
+        }
+        let stats = Arc::new(PeerStats::new(id, address, peer_id));
+        stats.limits().set(
+            self.config.peer_upload_limit,
+            self.config.peer_download_limit,
+        );
+        peers.insert(id, Arc::clone(&stats));
+        Ok(stats)
+    }
+
+    pub fn unregister_peer(&self, id: usize) {
+        self.peers.write().unwrap().remove(&id);
+    }
+
+    pub fn peer(&self, id: usize) -> Option<Arc<PeerStats>> {
+        self.peers.read().unwrap().get(&id).cloned()
+    }
+
+    pub fn peers(&self) -> Vec<Arc<PeerStats>> {
+        self.peers.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn global_limits(&self) -> &BandwidthLimits {
+        &self.global_limits
+    }
+
+    pub fn limits(&self) -> &BandwidthLimits {
+        &self.limits
+    }
+
+    pub fn disk_full(&self) -> bool {
+        self.disk_full.load(Ordering::Relaxed)
+    }
+
+    pub fn set_disk_full(&self, full: bool) {
+        self.disk_full.store(full, Ordering::Relaxed);
+    }
+
+    pub fn download_ended(&self) -> bool {
+        self.download_ended.load(Ordering::Relaxed)
+    }
+
+    //wakes whoever waits on subscribe, so it can stop waiting for missing pieces
+    pub fn set_download_ended(&self) {
+        self.download_ended.store(true, Ordering::Relaxed);
+        self.completed.send_modify(|_| ());
+    }
+
+    pub fn storage_error(&self) -> Option<String> {
+        self.storage_error.lock().unwrap().clone()
+    }
+
+    pub fn set_storage_error(&self, error: Option<String>) {
+        *self.storage_error.lock().unwrap() = error;
+    }
+
+    pub fn set_choker_decision(&self, decision: ChokerDecision) {
+        *self.choker_decision.lock().unwrap() = Some(decision);
+    }
+
+    pub fn stats(&self) -> TorrentStats {
+        let mut peers: Vec<_> = self.peers().iter().map(|p| p.snapshot()).collect();
+        peers.sort_by_key(|p| p.id);
+        TorrentStats {
+            pieces_completed: *self.completed.borrow(),
+            pieces_total: self.info().number_of_pieces(),
+            uploaded: self.uploaded(),
+            peers,
+            pool: self.pool.stats(),
+            ip_filter: self.config.ip_filter.stats(),
+            choker: self.choker_decision.lock().unwrap().clone(),
+            disk_full: self.disk_full(),
+            storage_error: self.storage_error(),
+        }
+    }
+}
@@ -0,0 +1,640 @@
+use crate::request::utp::packet::{HEADER_LENGTH, Packet, PacketType, seq_before};
+use crate::request::utp::{Connections, Transport};
+use bytes::Bytes;
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep_until;
+
+//payload of a full packet, keeps the datagram under the usual 1500 bytes MTU
+pub const MAX_PAYLOAD: usize = 1400 - HEADER_LENGTH;
+//bytes we buffer for the application before the advertised window closes
+const RECEIVE_BUFFER: usize = 1024 * 1024;
+//packets past the next expected one we keep for reordering
+const REORDER_LIMIT: u16 = 1024;
+//selective ack bitmask, 32 bits as in libutp
+const SELECTIVE_ACK_BYTES: usize = 4;
+
+//LEDBAT aims at this queuing delay, above it the window shrinks
+const TARGET_DELAY: Duration = Duration::from_millis(100);
+//bytes the window can grow in one round trip when there is no queuing delay
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = RECEIVE_BUFFER as f64;
+//the base delay is the lowest one seen in the last two minutes, kept per minute
+const BASE_DELAY_BUCKET: Duration = Duration::from_secs(60);
+const BASE_DELAY_BUCKETS: usize = 2;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+//timeouts in a row before the connection is given up
+const MAX_TIMEOUTS: u32 = 8;
+const MAX_SYN_TIMEOUTS: u32 = 3;
+//a packet is lost when this many packets after it were acked
+const LOSS_THRESHOLD: usize = 3;
+//a connection the peer stopped talking on, past the two minutes between keep-alives
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+//timeouts we wait for the peer after our FIN before the connection is dropped
+const LINGER_TIMEOUTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    //given up for lost after a timeout, it doesn't count in the window until sent again
+    need_resend: bool,
+}
+
+//Removes the connection from the socket when its task ends, however it ends: closed, reset,
+//timed out or aborted. It is taken before the first await of the task for that reason.
+struct Registration {
+    connections: Connections,
+    key: (SocketAddr, u16),
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.key);
+    }
+}
+
+//One uTP connection, driven by its own task. The application talks to it through the other end
+//of a duplex pipe, datagrams come from the socket task.
+pub struct Connection {
+    socket: Arc<Transport>,
+    remote: SocketAddr,
+    epoch: Instant,
+    send_id: u16,
+    state: State,
+    //next sequence number we send
+    seq_nr: u16,
+    //last sequence number received in order
+    ack_nr: u16,
+
+    in_flight: VecDeque<SentPacket>,
+    //payload bytes sent and not acked yet
+    cur_window: usize,
+    max_window: f64,
+    peer_window: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    retransmit_at: Option<Instant>,
+    timeouts: u32,
+    last_received: Instant,
+    last_ack: u16,
+    duplicate_acks: usize,
+    //bucket start and lowest delay in microseconds
+    base_delays: VecDeque<(Instant, u32)>,
+    //what we tell the peer about its packets, the last one-way delay we saw
+    reply_micros: u32,
+
+    reorder: HashMap<u16, Vec<u8>>,
+    received: VecDeque<Bytes>,
+    received_bytes: usize,
+    fin_received: Option<u16>,
+    fin_sent: bool,
+    //the application shut down or dropped its side
+    app_write_closed: bool,
+    app_read_closed: bool,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<Transport>,
+        remote: SocketAddr,
+        epoch: Instant,
+        send_id: u16,
+        seq_nr: u16,
+        state: State,
+    ) -> Self {
+        Self {
+            socket,
+            remote,
+            epoch,
+            send_id,
+            state,
+            seq_nr,
+            ack_nr: 0,
+            in_flight: VecDeque::new(),
+            cur_window: 0,
+            max_window: 2.0 * MIN_WINDOW,
+            peer_window: MAX_PAYLOAD,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            retransmit_at: None,
+            timeouts: 0,
+            last_received: Instant::now(),
+            last_ack: 0,
+            duplicate_acks: 0,
+            base_delays: VecDeque::new(),
+            reply_micros: 0,
+            reorder: HashMap::new(),
+            received: VecDeque::new(),
+            received_bytes: 0,
+            fin_received: None,
+            fin_sent: false,
+            app_write_closed: false,
+            app_read_closed: false,
+        }
+    }
+
+    //our side sends a SYN with recv_id, the peer answers on recv_id + 1
+    pub async fn connect(
+        socket: Arc<Transport>,
+        remote: SocketAddr,
+        epoch: Instant,
+        recv_id: u16,
+        registration: (Connections, mpsc::UnboundedReceiver<Packet>),
+        app: DuplexStream,
+        connected: oneshot::Sender<()>,
+    ) {
+        let (connections, packets) = registration;
+        let registration = Registration {
+            connections,
+            key: (remote, recv_id),
+        };
+        let mut connection = Self::new(
+            socket,
+            remote,
+            epoch,
+            recv_id.wrapping_add(1),
+            1,
+            State::SynSent,
+        );
+        let mut syn = Packet::new(PacketType::Syn, recv_id, 0, 0);
+        syn.seq_nr = connection.seq_nr;
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+        connection.transmit(syn).await;
+        connection
+            .run(registration, packets, app, Some(connected))
+            .await;
+    }
+
+    //answers the SYN of the peer, we receive on its id + 1 and send on its id
+    pub async fn accept(
+        socket: Arc<Transport>,
+        remote: SocketAddr,
+        epoch: Instant,
+        syn: Packet,
+        registration: (Connections, mpsc::UnboundedReceiver<Packet>),
+        app: DuplexStream,
+    ) {
+        let (connections, packets) = registration;
+        let registration = Registration {
+            connections,
+            key: (remote, syn.connection_id.wrapping_add(1)),
+        };
+        let mut connection = Self::new(
+            socket,
+            remote,
+            epoch,
+            syn.connection_id,
+            rand::random(),
+            State::Connected,
+        );
+        connection.ack_nr = syn.seq_nr;
+        connection.last_ack = connection.seq_nr.wrapping_sub(1);
+        connection.on_timestamps(&syn);
+        connection.send_ack().await;
+        connection.run(registration, packets, app, None).await;
+    }
+
+    async fn run(
+        mut self,
+        _registration: Registration,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+        app: DuplexStream,
+        mut connected: Option<oneshot::Sender<()>>,
+    ) {
+        let (mut app_reader, mut app_writer) = tokio::io::split(app);
+        let mut read_buf = vec![0u8; MAX_PAYLOAD];
+        loop {
+            let can_send = self.state == State::Connected
+                && !self.app_read_closed
+                && !self.fin_sent
+                && self.window_open();
+            //the futures own what they use, so the branches can change the connection
+            let deliver = self.received.front().cloned();
+            let retransmit_at = self.retransmit_at;
+            //the peer went silent, or it takes too long to close after our FIN
+            let idle_at = self.last_received
+                + match self.fin_sent {
+                    true => self.timeout * LINGER_TIMEOUTS,
+                    false => IDLE_TIMEOUT,
+                };
+            let result = tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => self.on_packet(packet).await,
+                    None => return,
+                },
+                read = app_reader.read(&mut read_buf), if can_send => {
+                    match read {
+                        Ok(0) | Err(_) => {
+                            self.app_read_closed = true;
+                            self.send_fin().await;
+                        }
+                        Ok(length) => self.send_data(&read_buf[..length]).await,
+                    }
+                    Ok(())
+                }
+                written = write_chunk(&mut app_writer, deliver.clone()), if deliver.is_some() => {
+                    match written {
+                        Ok(length) => self.on_delivered(length).await,
+                        //nobody reads anymore, what arrives is thrown away
+                        Err(_) => {
+                            self.app_write_closed = true;
+                            self.received.clear();
+                            self.received_bytes = 0;
+                        }
+                    }
+                    Ok(())
+                }
+                _ = sleep_until(retransmit_at.unwrap_or_else(Instant::now).into()),
+                    if retransmit_at.is_some() => self.on_timeout().await,
+                _ = sleep_until(idle_at.into()) => Err(io::ErrorKind::TimedOut.into()),
+            };
+            if let Err(e) = result {
+                debug!("uTP connection with {} closed: {}", self.remote, e);
+                return;
+            }
+            self.resend_marked().await;
+            if self.state == State::Connected
+                && let Some(connected) = connected.take()
+            {
+                let _ = connected.send(());
+            }
+            //the peer finished and everything before its FIN reached the application
+            if self.fin_received == Some(self.ack_nr)
+                && self.received.is_empty()
+                && !self.app_write_closed
+            {
+                let _ = app_writer.shutdown().await;
+                self.app_write_closed = true;
+            }
+            if self.app_write_closed && self.fin_sent && self.in_flight.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn window_open(&self) -> bool {
+        self.has_room(MAX_PAYLOAD)
+    }
+
+    fn has_room(&self, length: usize) -> bool {
+        let window = (self.max_window as usize).min(self.peer_window);
+        //one packet is always allowed, it probes a closed window
+        self.cur_window == 0 || self.cur_window + length <= window
+    }
+
+    fn now_micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    fn advertised_window(&self) -> u32 {
+        let buffered = self.received_bytes + self.reorder.values().map(Vec::len).sum::<usize>();
+        RECEIVE_BUFFER.saturating_sub(buffered) as u32
+    }
+
+    //fills the header fields that change at every transmission
+    async fn transmit(&mut self, mut packet: Packet) {
+        packet.timestamp = self.now_micros();
+        packet.timestamp_difference = self.reply_micros;
+        packet.window = self.advertised_window();
+        if packet.packet_type != PacketType::Syn {
+            packet.ack_nr = self.ack_nr;
+            packet.connection_id = self.send_id;
+        }
+        let bytes = packet.to_bytes();
+        if packet.packet_type != PacketType::State {
+            let now = Instant::now();
+            match self
+                .in_flight
+                .iter_mut()
+                .find(|s| s.packet.seq_nr == packet.seq_nr)
+            {
+                Some(sent) => {
+                    sent.sent_at = now;
+                    sent.transmissions += 1;
+                    if sent.need_resend {
+                        sent.need_resend = false;
+                        self.cur_window += packet.payload.len();
+                    }
+                }
+                None => {
+                    self.cur_window += packet.payload.len();
+                    self.in_flight.push_back(SentPacket {
+                        packet,
+                        sent_at: now,
+                        transmissions: 1,
+                        need_resend: false,
+                    });
+                }
+            }
+            self.retransmit_at.get_or_insert(now + self.timeout);
+        }
+        //a lost datagram is the same as a dropped one, the retransmission takes care of it
+        if let Err(e) = self.socket.send_to(&bytes, self.remote).await {
+            debug!("uTP cannot send to {}: {}", self.remote, e);
+        }
+    }
+
+    async fn send_data(&mut self, payload: &[u8]) {
+        let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, self.ack_nr);
+        packet.payload = payload.to_vec();
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(packet).await;
+    }
+
+    async fn send_fin(&mut self) {
+        if self.fin_sent || self.state != State::Connected {
+            return;
+        }
+        let packet = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, self.ack_nr);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.fin_sent = true;
+        self.transmit(packet).await;
+    }
+
+    async fn send_ack(&mut self) {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq_nr, self.ack_nr);
+        if !self.reorder.is_empty() {
+            let mut mask = vec![0u8; SELECTIVE_ACK_BYTES];
+            for bit in 0..SELECTIVE_ACK_BYTES * 8 {
+                let seq = self.ack_nr.wrapping_add(2 + bit as u16);
+                if self.reorder.contains_key(&seq) {
+                    mask[bit / 8] |= 1 << (bit % 8);
+                }
+            }
+            packet.selective_ack = Some(mask);
+        }
+        self.transmit(packet).await;
+    }
+
+    async fn resend(&mut self, index: usize) {
+        let packet = self.in_flight[index].packet.clone();
+        self.transmit(packet).await;
+    }
+
+    //after a timeout the packets go again, oldest first, as the window allows
+    async fn resend_marked(&mut self) {
+        for index in 0..self.in_flight.len() {
+            let sent = &self.in_flight[index];
+            if sent.need_resend && self.has_room(sent.packet.payload.len()) {
+                self.resend(index).await;
+            }
+        }
+    }
+
+    fn on_timestamps(&mut self, packet: &Packet) {
+        self.reply_micros = self.now_micros().wrapping_sub(packet.timestamp);
+    }
+
+    async fn on_packet(&mut self, packet: Packet) -> io::Result<()> {
+        self.last_received = Instant::now();
+        self.on_timestamps(&packet);
+        match packet.packet_type {
+            PacketType::Reset => return Err(io::ErrorKind::ConnectionReset.into()),
+            //our answer to the SYN got lost
+            PacketType::Syn => {
+                self.send_ack().await;
+                return Ok(());
+            }
+            PacketType::State if self.state == State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                self.last_ack = packet.ack_nr;
+            }
+            _ if self.state == State::SynSent => return Ok(()),
+            _ => (),
+        }
+        self.on_ack(&packet).await;
+        match packet.packet_type {
+            PacketType::Data => {
+                self.on_data(packet.seq_nr, packet.payload);
+                self.send_ack().await;
+            }
+            PacketType::Fin => {
+                self.fin_received = Some(packet.seq_nr);
+                self.on_data(packet.seq_nr, Vec::new());
+                self.send_ack().await;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn on_data(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        //already received, or too far ahead to keep
+        if ahead == 0
+            || ahead > REORDER_LIMIT
+            || self.fin_received.is_some_and(|fin| seq_before(fin, seq_nr))
+        {
+            return;
+        }
+        self.reorder.entry(seq_nr).or_insert(payload);
+        while let Some(payload) = self.reorder.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            if !payload.is_empty() && !self.app_write_closed {
+                self.received_bytes += payload.len();
+                self.received.push_back(Bytes::from(payload));
+            }
+        }
+    }
+
+    async fn on_delivered(&mut self, length: usize) {
+        let was_closed = (self.advertised_window() as usize) < MAX_PAYLOAD;
+        let front = self.received.front_mut().unwrap();
+        if length == front.len() {
+            self.received.pop_front();
+        } else {
+            *front = front.slice(length..);
+        }
+        self.received_bytes -= length;
+        //the peer stopped sending on a closed window, tell it there is room again
+        if was_closed && self.advertised_window() as usize >= MAX_PAYLOAD {
+            self.send_ack().await;
+        }
+    }
+
+    async fn on_ack(&mut self, packet: &Packet) {
+        let now = Instant::now();
+        self.peer_window = packet.window as usize;
+        let selected: Vec<u16> = packet.selectively_acked().collect();
+        let mut bytes_acked = 0;
+        let mut window_acked = 0;
+        let mut packets_acked = 0;
+        let mut rtt_sample = None;
+        self.in_flight.retain(|sent| {
+            let seq_nr = sent.packet.seq_nr;
+            let acked = !seq_before(packet.ack_nr, seq_nr) || selected.contains(&seq_nr);
+            if acked {
+                packets_acked += 1;
+                bytes_acked += sent.packet.payload.len();
+                if !sent.need_resend {
+                    window_acked += sent.packet.payload.len();
+                }
+                //a retransmitted packet doesn't tell which copy was acked, and an older one
+                //acked together with it waited for the ack that got lost
+                if sent.transmissions == 1 && seq_nr == packet.ack_nr {
+                    rtt_sample = Some(now - sent.sent_at);
+                }
+            }
+            !acked
+        });
+        self.cur_window -= window_acked.min(self.cur_window);
+        let progress = seq_before(self.last_ack, packet.ack_nr);
+        if progress {
+            self.last_ack = packet.ack_nr;
+            self.duplicate_acks = 0;
+        } else if packet.packet_type == PacketType::State
+            && packet.ack_nr == self.last_ack
+            && !self.in_flight.is_empty()
+        {
+            self.duplicate_acks += 1;
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.on_rtt(sample);
+        }
+        if packets_acked > 0 {
+            //the peer is there again, the backoff of the timeouts before is over
+            self.timeouts = 0;
+            self.timeout = self.base_timeout();
+            self.retransmit_at = (!self.in_flight.is_empty()).then_some(now + self.timeout);
+        }
+        if bytes_acked > 0 && packet.timestamp_difference != 0 {
+            self.on_delay(packet.timestamp_difference, bytes_acked, now);
+        }
+
+        //a packet is lost when three acks came after it, or three packets after it arrived
+        let mut lost = Vec::new();
+        if self.duplicate_acks >= LOSS_THRESHOLD {
+            self.duplicate_acks = 0;
+            lost.push(0);
+        }
+        for (index, sent) in self.in_flight.iter().enumerate() {
+            let later = selected
+                .iter()
+                .filter(|seq_nr| seq_before(sent.packet.seq_nr, **seq_nr))
+                .count();
+            if later >= LOSS_THRESHOLD && !lost.contains(&index) {
+                lost.push(index);
+            }
+        }
+        let mut resent = false;
+        for index in lost {
+            //sent again less than a round trip ago, the ack couldn't know yet
+            let sent = &self.in_flight[index];
+            let recent =
+                sent.transmissions > 1 && self.rtt.is_some_and(|rtt| now - sent.sent_at < rtt);
+            if !recent {
+                self.resend(index).await;
+                resent = true;
+            }
+        }
+        if resent {
+            self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+        }
+    }
+
+    fn on_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = self.rtt_var.mul_f64(0.75) + delta.mul_f64(0.25);
+                self.rtt = Some(rtt.mul_f64(0.875) + sample.mul_f64(0.125));
+            }
+        }
+        self.timeout = self.base_timeout();
+    }
+
+    fn base_timeout(&self) -> Duration {
+        match self.rtt {
+            Some(rtt) => (rtt + 4 * self.rtt_var).clamp(MIN_TIMEOUT, MAX_TIMEOUT),
+            None => INITIAL_TIMEOUT,
+        }
+    }
+
+    //LEDBAT: the window grows while the queuing delay is under target and shrinks above it
+    fn on_delay(&mut self, delay: u32, bytes_acked: usize, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((start, lowest)) if now - *start < BASE_DELAY_BUCKET => {
+                *lowest = (*lowest).min(delay);
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_BUCKETS {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base = self
+            .base_delays
+            .iter()
+            .map(|(_, lowest)| *lowest)
+            .min()
+            .unwrap();
+        let queuing = delay.wrapping_sub(base) as f64;
+        let target = TARGET_DELAY.as_micros() as f64;
+        let off_target = (target - queuing) / target;
+        let window_factor = bytes_acked as f64 / self.max_window.max(bytes_acked as f64);
+        self.max_window = (self.max_window + MAX_WINDOW_GAIN * off_target * window_factor)
+            .clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    async fn on_timeout(&mut self) -> io::Result<()> {
+        if self.in_flight.is_empty() {
+            self.retransmit_at = None;
+            return Ok(());
+        }
+        self.timeouts += 1;
+        let limit = match self.state {
+            State::SynSent => MAX_SYN_TIMEOUTS,
+            State::Connected => MAX_TIMEOUTS,
+        };
+        if self.timeouts > limit {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.max_window = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        for sent in &mut self.in_flight {
+            if !sent.need_resend {
+                sent.need_resend = true;
+                self.cur_window -= sent.packet.payload.len();
+            }
+        }
+        self.retransmit_at = Some(Instant::now() + self.timeout);
+        Ok(())
+    }
+}
+
+//owns the chunk, so the select branch borrows nothing from the connection
+async fn write_chunk(
+    writer: &mut WriteHalf<DuplexStream>,
+    chunk: Option<Bytes>,
+) -> io::Result<usize> {
+    match chunk {
+        Some(chunk) => writer.write(&chunk).await,
+        None => Ok(0),
+    }
+}
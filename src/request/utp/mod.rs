@@ -0,0 +1,403 @@
+mod connection;
+mod packet;
+
+use crate::request::proxy::ProxyDatagram;
+use crate::request::task::AbortOnDrop;
+use crate::request::utp::connection::Connection;
+use crate::request::utp::packet::{Packet, PacketType};
+use log::debug;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+//bytes buffered between a connection and the application, both ways
+const STREAM_BUFFER: usize = 256 * 1024;
+//connections the peers opened and nobody accepted yet, past this they are reset
+const ACCEPT_QUEUE: usize = 16;
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+//the packets of a connection go by remote address and the connection id we receive on
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+
+//Where the datagrams of a uTP socket go: straight to the peers or through a SOCKS5 relay
+enum Transport {
+    Direct(UdpSocket),
+    Proxied(ProxyDatagram),
+}
+
+impl Transport {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        match self {
+            Self::Direct(socket) => socket.send_to(buf, target).await,
+            Self::Proxied(datagram) => datagram.send_to(buf, target).await,
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Direct(socket) => socket.recv_from(buf).await,
+            Self::Proxied(datagram) => datagram.recv_from(buf).await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Direct(socket) => socket.local_addr(),
+            Self::Proxied(datagram) => datagram.local_addr(),
+        }
+    }
+}
+
+//A UDP socket carrying uTP (BEP 29) connections, opened and accepted like TCP ones. Every
+//connection runs in its own task, a receive task hands each datagram to the right one.
+pub struct UtpSocket {
+    socket: Arc<Transport>,
+    connections: Connections,
+    epoch: Instant,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    _receive_task: AbortOnDrop,
+}
+
+impl UtpSocket {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address).await?;
+        Ok(Self::new(Transport::Direct(socket)))
+    }
+
+    //every datagram goes through the proxy, peers only see its address
+    pub fn through_proxy(datagram: ProxyDatagram) -> Self {
+        Self::new(Transport::Proxied(datagram))
+    }
+
+    fn new(socket: Transport) -> Self {
+        let socket = Arc::new(socket);
+        let connections: Connections = Arc::default();
+        let epoch = Instant::now();
+        let (accepted, incoming) = mpsc::channel(ACCEPT_QUEUE);
+        let receive_task = tokio::spawn(Self::receive_loop(
+            Arc::clone(&socket),
+            Arc::clone(&connections),
+            epoch,
+            accepted,
+        ));
+        Self {
+            socket,
+            connections,
+            epoch,
+            incoming: tokio::sync::Mutex::new(incoming),
+            _receive_task: AbortOnDrop(receive_task),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn connect(&self, address: SocketAddr) -> io::Result<UtpStream> {
+        let (sender, packets) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut connections = self.connections.lock().unwrap();
+            let mut recv_id: u16 = rand::random();
+            //the peer answers on recv_id + 1, both must be free
+            while connections.contains_key(&(address, recv_id))
+                || connections.contains_key(&(address, recv_id.wrapping_add(1)))
+            {
+                recv_id = rand::random();
+            }
+            connections.insert((address, recv_id), sender);
+            recv_id
+        };
+        let (app, stream) = tokio::io::duplex(STREAM_BUFFER);
+        let (connected, is_connected) = oneshot::channel();
+        tokio::spawn(Connection::connect(
+            Arc::clone(&self.socket),
+            address,
+            self.epoch,
+            recv_id,
+            (Arc::clone(&self.connections), packets),
+            app,
+            connected,
+        ));
+        //the connection task drops the sender when the SYN is never answered
+        is_connected
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?;
+        Ok(UtpStream {
+            inner: stream,
+            peer_addr: address,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    async fn receive_loop(
+        socket: Arc<Transport>,
+        connections: Connections,
+        epoch: Instant,
+        accepted: mpsc::Sender<UtpStream>,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (length, from) = match socket.recv_from(&mut buf).await {
+                Ok(datagram) => datagram,
+                //e.g. an ICMP port unreachable for a datagram we sent
+                Err(e) => {
+                    debug!("uTP receive failed: {}", e);
+                    continue;
+                }
+            };
+            let Ok(packet) = Packet::parse(&buf[..length]) else {
+                continue;
+            };
+            //a SYN carries the id the peer receives on, we receive on the next one
+            let recv_id = match packet.packet_type {
+                PacketType::Syn => packet.connection_id.wrapping_add(1),
+                _ => packet.connection_id,
+            };
+            let connection = connections.lock().unwrap().get(&(from, recv_id)).cloned();
+            match connection {
+                Some(connection) => {
+                    let _ = connection.send(packet);
+                }
+                None if packet.packet_type == PacketType::Syn => {
+                    Self::on_syn(&socket, &connections, epoch, &accepted, from, packet).await;
+                }
+                None if matches!(packet.packet_type, PacketType::Data | PacketType::Fin) => {
+                    Self::reset(&socket, from, &packet).await;
+                }
+                None => (),
+            }
+        }
+    }
+
+    async fn on_syn(
+        socket: &Arc<Transport>,
+        connections: &Connections,
+        epoch: Instant,
+        accepted: &mpsc::Sender<UtpStream>,
+        from: SocketAddr,
+        syn: Packet,
+    ) {
+        let Ok(permit) = accepted.try_reserve() else {
+            debug!("uTP accept queue full, resetting {}", from);
+            Self::reset(socket, from, &syn).await;
+            return;
+        };
+        let (sender, packets) = mpsc::unbounded_channel();
+        connections
+            .lock()
+            .unwrap()
+            .insert((from, syn.connection_id.wrapping_add(1)), sender);
+        let (app, stream) = tokio::io::duplex(STREAM_BUFFER);
+        permit.send(UtpStream {
+            inner: stream,
+            peer_addr: from,
+        });
+        tokio::spawn(Connection::accept(
+            Arc::clone(socket),
+            from,
+            epoch,
+            syn,
+            (Arc::clone(connections), packets),
+            app,
+        ));
+    }
+
+    async fn reset(socket: &Transport, to: SocketAddr, packet: &Packet) {
+        let reset = Packet::new(PacketType::Reset, packet.connection_id, 0, packet.seq_nr);
+        let _ = socket.send_to(&reset.to_bytes(), to).await;
+    }
+}
+
+//A uTP connection, used like a TcpStream. Dropping it closes the connection once the data
+//written so far is acked.
+pub struct UtpStream {
+    inner: DuplexStream,
+    peer_addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    //forwards datagrams between one client and the server, dropping one in every drop_every
+    async fn lossy_relay(server: SocketAddr, drop_every: usize) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let mut count = 0;
+            loop {
+                let (length, from) = relay.recv_from(&mut buf).await.unwrap();
+                count += 1;
+                if count % drop_every == 0 {
+                    continue;
+                }
+                let to = if from == server {
+                    match client {
+                        Some(client) => client,
+                        None => continue,
+                    }
+                } else {
+                    client = Some(from);
+                    server
+                };
+                relay.send_to(&buf[..length], to).await.unwrap();
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn transfer_over_lossy_loopback() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let relay = lossy_relay(server.local_addr().unwrap(), 7).await;
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        let transfer = async {
+            let (outgoing, incoming) = tokio::join!(client.connect(relay), server.accept());
+            let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+            let sender = async {
+                outgoing.write_all(&data).await.unwrap();
+                outgoing.shutdown().await.unwrap();
+                let mut reply = Vec::new();
+                outgoing.read_to_end(&mut reply).await.unwrap();
+                reply
+            };
+            let receiver = async {
+                let mut received = Vec::new();
+                incoming.read_to_end(&mut received).await.unwrap();
+                incoming.write_all(b"done").await.unwrap();
+                incoming.shutdown().await.unwrap();
+                received
+            };
+            tokio::join!(sender, receiver)
+        };
+        let (reply, received) = tokio::time::timeout(Duration::from_secs(60), transfer)
+            .await
+            .unwrap();
+        assert!(received == data);
+        assert_eq!(reply, b"done");
+    }
+
+    #[tokio::test]
+    async fn timed_out_connections_are_forgotten() {
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (outgoing, incoming) = tokio::join!(
+            client.connect(server.local_addr().unwrap()),
+            server.accept()
+        );
+        let (_outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+        //from here on nothing answers, the timeouts go by without waiting
+        tokio::time::pause();
+
+        //nobody answers the SYN
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let prober = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let connected = prober.connect(silent.local_addr().unwrap()).await;
+        assert!(matches!(connected, Err(e) if e.kind() == io::ErrorKind::TimedOut));
+        assert!(prober.connections.lock().unwrap().is_empty());
+
+        //the peer is gone once connected, what we write is never acked
+        drop(client);
+        incoming.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(!matches!(incoming.read(&mut buf).await, Ok(read) if read > 0));
+        assert!(server.connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_connections_are_closed() {
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (outgoing, incoming) = tokio::join!(
+            client.connect(server.local_addr().unwrap()),
+            server.accept()
+        );
+        let (mut outgoing, mut incoming) = (outgoing.unwrap(), incoming.unwrap());
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+
+        //our FIN is acked but the peer never closes its side
+        incoming.shutdown().await.unwrap();
+        let mut buf = [0u8; 1];
+        assert!(!matches!(incoming.read(&mut buf).await, Ok(read) if read > 0));
+        assert!(server.connections.lock().unwrap().is_empty());
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        //the peer closed and went silent, the other side stops waiting for it
+        assert!(matches!(outgoing.read(&mut buf).await, Ok(0)));
+        assert_eq!(client.connections.lock().unwrap().len(), 1);
+        drop(server);
+        tokio::time::sleep(Duration::from_secs(200)).await;
+        assert!(client.connections.lock().unwrap().is_empty());
+        assert!(outgoing.write_all(b"hello").await.is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+
+pub const HEADER_LENGTH: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_NONE: u8 = 0;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+//One uTP datagram. Timestamps are in microseconds and wrap around, only their differences
+//mean something.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_difference: u32,
+    pub window: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    //bit i set means ack_nr + 2 + i was received
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(packet_type: PacketType, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            packet_type,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr,
+            selective_ack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+        if bytes.len() < HEADER_LENGTH {
+            return Err(invalid("uTP packet shorter than its header"));
+        }
+        if bytes[0] & 0x0f != VERSION {
+            return Err(invalid("unknown uTP version"));
+        }
+        let packet_type = PacketType::from_u8(bytes[0] >> 4).ok_or(invalid("unknown uTP type"))?;
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_LENGTH;
+        while extension != EXTENSION_NONE {
+            if bytes.len() < at + 2 {
+                return Err(invalid("truncated uTP extension"));
+            }
+            let next = bytes[at];
+            let length = bytes[at + 1] as usize;
+            let data = bytes
+                .get(at + 2..at + 2 + length)
+                .ok_or(invalid("truncated uTP extension"))?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            at += 2 + length;
+        }
+        Ok(Self {
+            packet_type,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push((self.packet_type as u8) << 4 | VERSION);
+        bytes.push(match self.selective_ack {
+            Some(_) => EXTENSION_SELECTIVE_ACK,
+            None => EXTENSION_NONE,
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq_nr.to_be_bytes());
+        bytes.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(EXTENSION_NONE);
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    //the sequence numbers past ack_nr + 1 the selective ack reports as received
+    pub fn selectively_acked(&self) -> impl Iterator<Item = u16> + '_ {
+        let ack_nr = self.ack_nr;
+        self.selective_ack.iter().flat_map(move |mask| {
+            mask.iter().enumerate().flat_map(move |(byte, bits)| {
+                (0..8)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| ack_nr.wrapping_add(2 + (byte * 8 + bit) as u16))
+            })
+        })
+    }
+}
+
+//true if a comes before b, sequence numbers wrap around
+pub fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_round_trip_with_selective_ack() {
+        let mut packet = Packet::new(PacketType::State, 1234, 10, u16::MAX);
+        packet.timestamp = 42;
+        packet.window = 1 << 20;
+        packet.selective_ack = Some(vec![0b0000_0101, 0, 0, 0b1000_0000]);
+        let parsed = Packet::parse(&packet.to_bytes()).unwrap();
+        assert_eq!(parsed, packet);
+        assert_eq!(
+            parsed.selectively_acked().collect::<Vec<_>>(),
+            vec![1, 3, 32]
+        );
+        assert!(seq_before(u16::MAX, 0));
+        assert!(!seq_before(5, 5));
+    }
+}
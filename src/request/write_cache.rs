@@ -0,0 +1,306 @@
+use crate::request::client::ClientError;
+use crate::request::resume::ResumeFile;
+use crate::request::torrent_state::TorrentState;
+use log::{debug, warn};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::task::JoinHandle;
+
+//how long to wait before trying a write that failed on a full disk again
+const DISK_FULL_RETRY: Duration = Duration::from_secs(10);
+
+struct CachedPiece {
+    index: usize,
+    data: Vec<u8>,
+    //the bytes of the cache this piece takes, given back once it is written
+    _room: OwnedSemaphorePermit,
+}
+
+//Verified pieces on their way to storage. A task writes them in the background, as many as are
+//waiting at once, and only then tells the peers we have them. The bytes held are capped: insert
+//waits for room when the disk is slower than the network.
+pub struct WriteCache {
+    room: Arc<Semaphore>,
+    capacity: usize,
+    pieces: Option<mpsc::UnboundedSender<CachedPiece>>,
+    flusher: Option<JoinHandle<Result<(), ClientError>>>,
+}
+
+impl WriteCache {
+    //a piece bigger than capacity still gets in, alone
+    pub fn new(state: Arc<TorrentState>, resume: ResumeFile, capacity: usize) -> Self {
+        let capacity = capacity.clamp(1, Semaphore::MAX_PERMITS);
+        let (pieces, receiver) = mpsc::unbounded_channel();
+        Self {
+            room: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            pieces: Some(pieces),
+            flusher: Some(tokio::spawn(Self::flush_loop(state, resume, receiver))),
+        }
+    }
+
+    pub async fn insert(&mut self, index: usize, data: Vec<u8>) -> Result<(), ClientError> {
+        let needed = data.len().clamp(1, self.capacity) as u32;
+        let room = Arc::clone(&self.room)
+            .acquire_many_owned(needed)
+            .await
+            .map_err(|_| ClientError::ChannelReceiverError)?;
+        let piece = CachedPiece {
+            index,
+            data,
+            _room: room,
+        };
+        let sent = match &self.pieces {
+            Some(pieces) => pieces.send(piece).is_ok(),
+            None => false,
+        };
+        if !sent {
+            //the flusher stopped on a storage error, that is the one to report
+            return Err(self
+                .stop()
+                .await
+                .err()
+                .unwrap_or(ClientError::ChannelReceiverError));
+        }
+        Ok(())
+    }
+
+    //bytes waiting to be written
+    pub fn cached(&self) -> usize {
+        self.capacity - self.room.available_permits()
+    }
+
+    //waits until every piece inserted is written
+    pub async fn finish(mut self) -> Result<(), ClientError> {
+        self.stop().await
+    }
+
+    async fn stop(&mut self) -> Result<(), ClientError> {
+        self.pieces = None;
+        match self.flusher.take() {
+            Some(flusher) => flusher
+                .await
+                .map_err(|_| ClientError::ChannelReceiverError)?,
+            None => Ok(()),
+        }
+    }
+
+    async fn flush_loop(
+        state: Arc<TorrentState>,
+        resume: ResumeFile,
+        mut receiver: mpsc::UnboundedReceiver<CachedPiece>,
+    ) -> Result<(), ClientError> {
+        while let Some(first) = receiver.recv().await {
+            let mut batch = vec![first];
+            while let Ok(piece) = receiver.try_recv() {
+                batch.push(piece);
+            }
+            //the pieces stay in the cache meanwhile, so the peers stop downloading once it is full
+            loop {
+                match Self::write_batch(&state, &batch).await {
+                    Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+                        if !state.disk_full() {
+                            warn!("Disk full, pausing the download until there is room");
+                            state.set_disk_full(true);
+                        }
+                        tokio::time::sleep(DISK_FULL_RETRY).await;
+                    }
+                    //the data is closed after a failed move, the pieces wait for the next one
+                    Err(_) if state.storage_error().is_some() => {
+                        tokio::time::sleep(DISK_FULL_RETRY).await;
+                    }
+                    result => break result?,
+                }
+            }
+            if state.disk_full() {
+                debug!("Disk has room again, resuming the download");
+                state.set_disk_full(false);
+            }
+            debug!("Wrote {} pieces to storage", batch.len());
+            //the piece must already be readable from storage, since peers can ask for it
+            for piece in &batch {
+                state.piece_completed(piece.index);
+            }
+            //the resume data lists the blocks of unfinished pieces once they are on disk
+            if let Err(e) = state.store_unfinished().await {
+                warn!("Cannot write the blocks of unfinished pieces: {}", e);
+            }
+            resume.save(&state).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_batch(state: &TorrentState, batch: &[CachedPiece]) -> io::Result<()> {
+        let mut storage = state.storage().lock().await;
+        for piece in batch {
+            storage.write_block(piece.index, 0, &piece.data).await?;
+        }
+        storage.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::torrent_file::TorrentFile;
+    use crate::request::bitfield::Bitfield;
+    use crate::request::config::ClientConfig;
+    use crate::request::rate_limit::BandwidthLimits;
+    use crate::request::storage::{FailedStorage, MemoryStorage, Storage};
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    fn torrent() -> TorrentFile {
+        serde_bencode::from_bytes(
+            b"d4:infod6:lengthi12e4:name5:cache12:piece lengthi4e6:pieces60:\
+              aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee",
+        )
+        .unwrap()
+    }
+
+    //writes fail like on a full disk until full is cleared
+    struct FullDisk {
+        inner: MemoryStorage,
+        full: Arc<AtomicBool>,
+    }
+
+    impl Storage for FullDisk {
+        fn read_block(
+            &mut self,
+            piece: usize,
+            begin: usize,
+            length: usize,
+        ) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+            self.inner.read_block(piece, begin, length)
+        }
+
+        fn write_block<'a>(
+            &'a mut self,
+            piece: usize,
+            begin: usize,
+            data: &'a [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            if self.full.load(Ordering::Relaxed) {
+                return Box::pin(async { Err(io::ErrorKind::StorageFull.into()) });
+            }
+            self.inner.write_block(piece, begin, data)
+        }
+
+        fn flush(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            self.inner.flush()
+        }
+
+        fn close(&mut self) -> BoxFuture<'_, io::Result<()>> {
+            self.inner.close()
+        }
+    }
+
+    #[tokio::test]
+    async fn insert_waits_for_room_and_pieces_end_up_in_storage() {
+        let storage = MemoryStorage::new(12, 4);
+        let state = Arc::new(TorrentState::new(
+            torrent(),
+            [1; 20],
+            ClientConfig::default(),
+            Bitfield::new(3),
+            Box::new(storage.clone()),
+            BandwidthLimits::unlimited(),
+        ));
+        let path = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        let resume = ResumeFile::new(path.to_str().unwrap(), Vec::new());
+        let mut cache = WriteCache::new(Arc::clone(&state), resume, 8);
+
+        //the storage is busy, the third piece has to wait for the first two to be written
+        let busy = state.storage().lock().await;
+        cache.insert(0, b"0000".to_vec()).await.unwrap();
+        cache.insert(1, b"1111".to_vec()).await.unwrap();
+        assert_eq!(cache.cached(), 8);
+        {
+            let third = cache.insert(2, b"2222".to_vec());
+            tokio::pin!(third);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), &mut third)
+                    .await
+                    .is_err()
+            );
+            drop(busy);
+            third.await.unwrap();
+        }
+        cache.finish().await.unwrap();
+
+        assert_eq!(storage.contents(), b"000011112222");
+        assert!((0..3).all(|index| state.has_piece(index)));
+        let _ = std::fs::remove_file(format!("{}.resume", path.display()));
+    }
+
+    //a torrent started short of space, the disk fills up for real and room is made later
+    #[tokio::test(start_paused = true)]
+    async fn full_disk_pauses_writes_until_there_is_room() {
+        let storage = MemoryStorage::new(12, 4);
+        let full = Arc::new(AtomicBool::new(true));
+        let disk = FullDisk {
+            inner: storage.clone(),
+            full: Arc::clone(&full),
+        };
+        let state = Arc::new(TorrentState::new(
+            torrent(),
+            [1; 20],
+            ClientConfig::default(),
+            Bitfield::new(3),
+            Box::new(disk),
+            BandwidthLimits::unlimited(),
+        ));
+        state.set_disk_full(true);
+        let path = std::env::temp_dir().join(format!("full-{}", std::process::id()));
+        let resume = ResumeFile::new(path.to_str().unwrap(), Vec::new());
+        let mut cache = WriteCache::new(Arc::clone(&state), resume, 8);
+
+        cache.insert(0, b"0000".to_vec()).await.unwrap();
+        tokio::time::sleep(3 * DISK_FULL_RETRY).await;
+        assert!(state.disk_full());
+        assert!(!state.has_piece(0));
+        assert_eq!(cache.cached(), 4);
+
+        full.store(false, Ordering::Relaxed);
+        tokio::time::sleep(DISK_FULL_RETRY).await;
+        cache.finish().await.unwrap();
+        assert!(!state.disk_full());
+        assert!(state.has_piece(0));
+        assert_eq!(&storage.contents()[..4], b"0000");
+        let _ = std::fs::remove_file(format!("{}.resume", path.display()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pieces_wait_for_data_that_failed_to_open() {
+        let state = Arc::new(TorrentState::new(
+            torrent(),
+            [1; 20],
+            ClientConfig::default(),
+            Bitfield::new(3),
+            Box::new(FailedStorage::new("gone")),
+            BandwidthLimits::unlimited(),
+        ));
+        state.set_storage_error(Some("gone".to_string()));
+        let path = std::env::temp_dir().join(format!("failed-{}", std::process::id()));
+        let resume = ResumeFile::new(path.to_str().unwrap(), Vec::new());
+        let mut cache = WriteCache::new(Arc::clone(&state), resume, 8);
+
+        cache.insert(0, b"0000".to_vec()).await.unwrap();
+        tokio::time::sleep(3 * DISK_FULL_RETRY).await;
+        assert!(!state.has_piece(0));
+        assert_eq!(cache.cached(), 4);
+
+        //moved again, the data opens
+        let storage = MemoryStorage::new(12, 4);
+        *state.storage().lock().await = Box::new(storage.clone());
+        state.set_storage_error(None);
+        tokio::time::sleep(DISK_FULL_RETRY).await;
+        cache.finish().await.unwrap();
+        assert!(state.has_piece(0));
+        assert_eq!(&storage.contents()[..4], b"0000");
+        let _ = std::fs::remove_file(format!("{}.resume", path.display()));
+    }
+}